@@ -0,0 +1,281 @@
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{
+    auth::{client_key, secret_eq},
+    config::{Cookie, CookieInfo, Settings, UselessCookie, UselessReason},
+    state::AppState,
+    usage::UsageTotals,
+};
+
+/// Routes of the admin API, to be nested under `/admin`
+pub fn admin_router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/status", get(get_status))
+        // cookies go in the body, paths end up in logs
+        .route(
+            "/cookies",
+            get(list_cookies).post(add_cookie).delete(remove_cookie),
+        )
+        .route("/cookies/waste", post(waste_cookie))
+        .route("/cookies/restore", post(restore_cookie))
+        .route("/requests", get(list_requests))
+        .route("/usage", get(get_usage))
+        .route("/settings", get(get_settings).put(put_settings))
+        .route("/rotate", post(rotate))
+        .route("/bootstrap", post(rebootstrap))
+        .route("/reload", post(reload))
+        .layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Let only requests carrying `admin_password` through
+pub async fn require_admin(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    req: Request,
+    next: Next,
+) -> Response {
+    let password = state.0.config.read().admin_password.clone();
+    if password.is_empty() {
+        return admin_error(
+            StatusCode::FORBIDDEN,
+            "Admin API is disabled, set admin_password in config",
+        );
+    }
+    if !client_key(&headers).is_some_and(|k| secret_eq(k, &password)) {
+        warn!("Admin request with invalid password");
+        return admin_error(StatusCode::UNAUTHORIZED, "Invalid admin password");
+    }
+    next.run(req).await
+}
+
+fn admin_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+async fn get_status(State(state): State<AppState>) -> Json<Value> {
+    let config = state.0.config.read();
+    let model = usize::try_from(config.index())
+        .ok()
+        .and_then(|i| config.cookie_array().get(i))
+        .and_then(|c| c.model.clone());
+    Json(json!({
+        "rotating": state.is_rotating(),
+        "is_pro": *state.0.is_pro.read(),
+        "uuid_org": *state.0.uuid_org.read(),
+        "model": model,
+        "cookie_index": config.index(),
+        "cookie_count": config.cookie_array_len(),
+        "wasted_count": config.wasted_cookie.len(),
+    }))
+}
+
+async fn list_cookies(State(state): State<AppState>) -> Json<Value> {
+    let config = state.0.config.read();
+    let counts = state.0.request_counts.read();
+    let now = chrono::Utc::now().timestamp();
+    let cookies = config
+        .cookie_array()
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let status = if i as i32 == config.index() {
+                "current"
+            } else if c.reset_time.is_some_and(|t| t > now) {
+                "temporary"
+            } else {
+                "healthy"
+            };
+            json!({
+                "index": i,
+                "cookie": c.cookie,
+                "status": status,
+                "model": c.model,
+                "group": c.group,
+                "tags": c.tags,
+                "reset_time": c.reset_time,
+                "requests": counts.get(&c.cookie).copied().unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let wasted = config
+        .wasted_cookie
+        .iter()
+        .map(|c| {
+            json!({
+                "cookie": c.cookie,
+                "reason": c.reason.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(json!({
+        "cookie_array": cookies,
+        "wasted_cookie": wasted,
+    }))
+}
+
+#[derive(Deserialize)]
+struct AddCookie {
+    cookie: String,
+    model: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+async fn add_cookie(State(state): State<AppState>, Json(body): Json<AddCookie>) -> Response {
+    let info = CookieInfo {
+        group: body.group,
+        tags: body.tags,
+        ..CookieInfo::new(&body.cookie, body.model.as_deref(), None)
+    };
+    if !info.cookie.validate() {
+        return admin_error(StatusCode::BAD_REQUEST, "Invalid cookie format");
+    }
+    // the first cookie of the pool becomes the current one
+    let _session = state.0.session.clone().write_owned().await;
+    let was_empty = {
+        let mut config = state.0.config.write();
+        let was_empty = config.cookie_array_len() == 0;
+        if !config.add_cookie(info) {
+            return admin_error(StatusCode::CONFLICT, "Cookie already exists");
+        }
+        config.save().ok();
+        was_empty
+    };
+    state.extend_init_length(1);
+    info!("Cookie added via admin API");
+    if was_empty {
+        state.rebootstrap();
+    }
+    (StatusCode::CREATED, Json(json!({ "added": true }))).into_response()
+}
+
+#[derive(Deserialize)]
+struct CookieRef {
+    cookie: String,
+}
+
+async fn remove_cookie(State(state): State<AppState>, Json(body): Json<CookieRef>) -> Response {
+    let cookie = Cookie::from(body.cookie.as_str());
+    // wait for the requests on the cookie, removing it shifts the pool
+    let _session = state.0.session.clone().write_owned().await;
+    let mut config = state.0.config.write();
+    if let Some((_, was_current)) = config.remove_cookie(&cookie) {
+        config.save().ok();
+        drop(config);
+        if was_current {
+            state.rebootstrap();
+        }
+        return Json(json!({ "removed": "cookie_array" })).into_response();
+    }
+    if config.remove_wasted(&cookie).is_some() {
+        config.save().ok();
+        return Json(json!({ "removed": "wasted_cookie" })).into_response();
+    }
+    admin_error(StatusCode::NOT_FOUND, "Cookie not found")
+}
+
+#[derive(Deserialize)]
+struct WasteCookie {
+    cookie: String,
+    reason: Option<UselessReason>,
+}
+
+async fn waste_cookie(State(state): State<AppState>, Json(body): Json<WasteCookie>) -> Response {
+    let cookie = Cookie::from(body.cookie.as_str());
+    let reason = body.reason.unwrap_or(UselessReason::Null);
+    let _session = state.0.session.clone().write_owned().await;
+    let mut config = state.0.config.write();
+    let Some((removed, was_current)) = config.remove_cookie(&cookie) else {
+        return admin_error(StatusCode::NOT_FOUND, "Cookie not found in cookie_array");
+    };
+    config
+        .wasted_cookie
+        .push(UselessCookie::new(removed.cookie, reason));
+    config.save().ok();
+    drop(config);
+    if was_current {
+        state.rebootstrap();
+    }
+    Json(json!({ "moved": "wasted_cookie" })).into_response()
+}
+
+async fn restore_cookie(State(state): State<AppState>, Json(body): Json<CookieRef>) -> Response {
+    let cookie = Cookie::from(body.cookie.as_str());
+    let _session = state.0.session.clone().write_owned().await;
+    let was_empty = {
+        let mut config = state.0.config.write();
+        let Some(wasted) = config.remove_wasted(&cookie) else {
+            return admin_error(StatusCode::NOT_FOUND, "Cookie not found in wasted_cookie");
+        };
+        let was_empty = config.cookie_array_len() == 0;
+        config.add_cookie(CookieInfo {
+            cookie: wasted.cookie,
+            model: None,
+            reset_time: None,
+            group: None,
+            tags: vec![],
+        });
+        config.save().ok();
+        was_empty
+    };
+    state.extend_init_length(1);
+    if was_empty {
+        state.rebootstrap();
+    }
+    Json(json!({ "moved": "cookie_array" })).into_response()
+}
+
+async fn list_requests(State(state): State<AppState>) -> Json<Value> {
+    let log = &state.0.request_log;
+    Json(json!({
+        "total": log.total(),
+        "errors": log.errors(),
+        "recent": log.recent(),
+    }))
+}
+
+async fn get_usage(State(state): State<AppState>) -> Json<UsageTotals> {
+    Json(state.0.usage.read().clone())
+}
+
+async fn get_settings(State(state): State<AppState>) -> Json<Settings> {
+    Json(state.0.config.read().settings.clone())
+}
+
+async fn put_settings(State(state): State<AppState>, Json(settings): Json<Settings>) -> Response {
+    let mut config = state.0.config.write();
+    config.settings = settings;
+    config.save().ok();
+    info!("Settings updated via admin API");
+    Json(config.settings.clone()).into_response()
+}
+
+async fn rotate(State(state): State<AppState>) -> Json<Value> {
+    info!("Forced cookie rotation via admin API");
+    state.force_rotate();
+    Json(json!({ "rotating": true }))
+}
+
+async fn rebootstrap(State(state): State<AppState>) -> Json<Value> {
+    info!("Re-bootstrap via admin API");
+    state.rebootstrap();
+    Json(json!({ "bootstrapping": true }))
+}
+
+async fn reload(State(state): State<AppState>) -> Response {
+    match state.reload_config().await {
+        Ok(changes) => Json(json!({ "changes": changes })).into_response(),
+        Err(e) => admin_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
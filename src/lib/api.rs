@@ -0,0 +1,216 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    response::Response,
+};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{sync::atomic::Ordering, time::Instant};
+use tracing::{info, warn};
+
+use crate::{
+    client::NORMAL_CLIENT,
+    config::Config,
+    error::ClewdrError,
+    metrics::{Metrics, timed_stream},
+    models::metric_label,
+    state::AppState,
+    thinking::strip_placeholder_thinking,
+};
+
+/// Official Anthropic API
+pub const API_ENDPOINT: &str = "https://api.anthropic.com";
+/// Request header to choose the backend, `web`, `api` or `rproxy`
+pub const BACKEND_HEADER: &str = "x-clewdr-backend";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Wait before retrying a rate limited key if the response does not say
+const DEFAULT_KEY_COOLDOWN: i64 = 60;
+
+/// Upstream that serves a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// claude.ai with web cookies
+    Web,
+    /// Official API with `sk-ant-api` keys
+    Api,
+    /// Another clewdr or compatible proxy at `fallback_rproxy`
+    Rproxy,
+}
+
+impl Backend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Web => "web",
+            Backend::Api => "api",
+            Backend::Rproxy => "rproxy",
+        }
+    }
+}
+
+/// Backend pinned by the request header
+pub fn requested_backend(headers: &HeaderMap) -> Option<Backend> {
+    match headers.get(BACKEND_HEADER).and_then(|h| h.to_str().ok()) {
+        Some("api") => Some(Backend::Api),
+        Some("web") => Some(Backend::Web),
+        Some("rproxy") => Some(Backend::Rproxy),
+        _ => None,
+    }
+}
+
+/// Choose the backend from the request header, then from `api_models`
+pub fn select_backend(headers: &HeaderMap, model: &str, config: &Config) -> Backend {
+    if let Some(backend) = requested_backend(headers) {
+        return backend;
+    }
+    if config.api_models.iter().any(|m| m == model) {
+        Backend::Api
+    } else {
+        Backend::Web
+    }
+}
+
+/// Stream an upstream response back with its status and content type
+pub fn passthrough(
+    res: rquest::Response,
+    metrics: &Metrics,
+    model: &str,
+    start: Instant,
+) -> Result<Response, ClewdrError> {
+    let status = StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string();
+    let stream = timed_stream(res.bytes_stream(), metrics, model, start);
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from_stream(stream))
+        .map_err(|_| ClewdrError::UnexpectedNone)
+}
+
+impl AppState {
+    /// Next usable API key that has not been tried for this request yet
+    fn next_api_key(&self, tried: &[String]) -> Option<String> {
+        let config = self.0.config.read();
+        let now = chrono::Utc::now().timestamp();
+        let len = config.api_keys.len();
+        let start = self.0.api_key_index.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|i| &config.api_keys[(start + i) % len])
+            .find(|k| !tried.contains(&k.key) && k.reset_time.is_none_or(|t| t <= now))
+            .map(|k| k.key.clone())
+    }
+
+    /// Forward a Messages API request body to the official API, rotating keys
+    /// on 401 and 429
+    pub async fn try_api_message(
+        &self,
+        body: Value,
+        headers: &HeaderMap,
+    ) -> Result<Response, ClewdrError> {
+        self.try_api_request("messages", body, headers).await
+    }
+
+    /// POST `body` to `/v1/{path}` of the official API with a key from the pool
+    ///
+    /// Thinking blocks clewdr signed with a placeholder are removed first.
+    pub async fn try_api_request(
+        &self,
+        path: &str,
+        mut body: Value,
+        headers: &HeaderMap,
+    ) -> Result<Response, ClewdrError> {
+        let start = Instant::now();
+        strip_placeholder_thinking(&mut body);
+        let (model, endpoint) = {
+            let config = self.0.config.read();
+            let model = metric_label(&config, body["model"].as_str().unwrap_or_default());
+            let base = if config.api_rproxy.is_empty() {
+                API_ENDPOINT
+            } else {
+                config.api_rproxy.as_str()
+            };
+            (model, format!("{}/v1/{}", base, path))
+        };
+        let version = headers
+            .get("anthropic-version")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or(ANTHROPIC_VERSION)
+            .to_string();
+        let beta = headers
+            .get("anthropic-beta")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+        let mut tried = vec![];
+        loop {
+            let Some(key) = self.next_api_key(&tried) else {
+                warn!("No usable API key left");
+                return Err(ClewdrError::NoValidKey);
+            };
+            tried.push(key.clone());
+            let mut req = NORMAL_CLIENT
+                .post(endpoint.as_str())
+                .header("x-api-key", key.as_str())
+                .header("anthropic-version", version.as_str())
+                .json(&body);
+            if let Some(beta) = &beta {
+                req = req.header("anthropic-beta", beta.as_str());
+            }
+            let request_start = Instant::now();
+            let res = req.send().await?;
+            self.0
+                .metrics
+                .observe_upstream(&format!("api_{}", path.replace('/', "_")), request_start);
+            let status = res.status().as_u16();
+            match status {
+                // 403 is about the request, e.g. a model the key may not use
+                401 => {
+                    warn!("API key rejected with 401, removing it");
+                    self.waste_api_key(&key);
+                    continue;
+                }
+                429 => {
+                    let retry_after = res
+                        .headers()
+                        .get("retry-after")
+                        .and_then(|h| h.to_str().ok())
+                        .and_then(|h| h.parse::<i64>().ok())
+                        .unwrap_or(DEFAULT_KEY_COOLDOWN);
+                    warn!("API key rate limited for {} seconds", retry_after);
+                    self.limit_api_key(&key, chrono::Utc::now().timestamp() + retry_after);
+                    continue;
+                }
+                500.. => {
+                    let text = res.text().await.unwrap_or_default();
+                    return Err(ClewdrError::UpstreamStatus(status, text));
+                }
+                _ => {}
+            }
+            return passthrough(res, &self.0.metrics, &model, start);
+        }
+    }
+
+    fn waste_api_key(&self, key: &str) {
+        let mut config = self.0.config.write();
+        let Some(pos) = config.api_keys.iter().position(|k| k.key == key) else {
+            return;
+        };
+        let removed = config.api_keys.remove(pos);
+        config.wasted_api_keys.push(removed.key);
+        config.save().ok();
+    }
+
+    fn limit_api_key(&self, key: &str, reset_time: i64) {
+        let mut config = self.0.config.write();
+        if let Some(info) = config.api_keys.iter_mut().find(|k| k.key == key) {
+            info.reset_time = Some(reset_time);
+            config.save().ok();
+        }
+        info!("API key resets at {}", reset_time.to_string().yellow());
+    }
+}
@@ -0,0 +1,75 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use crate::{clients::anthropic_error, state::AppState};
+
+/// Get the key sent by the client from `Authorization: Bearer` or `x-api-key`
+pub fn client_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.trim_start_matches("Bearer ").trim())
+        .or_else(|| headers.get("x-api-key").and_then(|h| h.to_str().ok()))
+}
+
+/// Whether `key` is `secret`, taking as long wherever they differ
+pub fn secret_eq(key: &str, secret: &str) -> bool {
+    key.len() == secret.len()
+        && key
+            .bytes()
+            .zip(secret.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Key sent by the client, without the OpenAI key clewd allows after `oaiKey:`
+pub fn proxy_key(headers: &HeaderMap) -> Option<&str> {
+    client_key(headers).map(|k| {
+        k.split("oaiKey:")
+            .next()
+            .unwrap_or(k)
+            .trim_end_matches(',')
+            .trim()
+    })
+}
+
+/// Resolve the key of an API request to a client, rejecting unknown keys
+///
+/// Keys are only required once `[[clients]]` are configured. A client key
+/// adds its `ClientInfo` to the request extensions, `proxy_password` is then
+/// accepted as a shared key and leaves the request anonymous. Without clients
+/// every request is let through and `proxy_password` is not checked, as in
+/// clewd.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let (password, has_clients) = {
+        let config = state.0.config.read();
+        (config.proxy_password.clone(), !config.clients.is_empty())
+    };
+    if !has_clients {
+        return next.run(req).await;
+    }
+    let key = proxy_key(&headers);
+    if let Some(client) = key.and_then(|k| state.find_client(k)) {
+        req.extensions_mut().insert(client);
+        return next.run(req).await;
+    }
+    if !password.is_empty() && key.is_some_and(|k| secret_eq(k, &password)) {
+        return next.run(req).await;
+    }
+    warn!("Request with invalid API key");
+    anthropic_error(
+        StatusCode::UNAUTHORIZED,
+        "authentication_error",
+        "Invalid API key",
+    )
+}
@@ -1,43 +1,43 @@
 use colored::Colorize;
 use serde_json::{Value, json};
+use tokio::sync::OwnedRwLockWriteGuard;
 use tracing::{error, warn};
 
 use crate::{
-    client::{AppendHeaders, SUPER_CLIENT},
     config::UselessReason,
     error::{ClewdrError, check_res_err},
+    models::is_known,
     state::AppState,
-    utils::{ENDPOINT, JsBool, MODELS},
+    utils::JsBool,
 };
 
 impl AppState {
+    /// Bootstrap the current cookie, once the requests on the session are done
     pub async fn bootstrap(&self) {
+        let session = self.0.session.clone().write_owned().await;
+        self.bootstrap_locked(&session).await;
+    }
+
+    /// Bootstrap the current cookie, the caller holds `session` exclusively
+    pub(crate) async fn bootstrap_locked(&self, session: &OwnedRwLockWriteGuard<()>) {
         let istate = self.0.clone();
         {
             let mut config = istate.config.write();
             if let Some(current_cookie) = config.current_cookie_info().cloned() {
                 config.cookie = current_cookie.cookie.clone();
-                if istate.model.read().is_some()
-                    && current_cookie.model.is_some()
-                    && !current_cookie.is_pro()
-                    && istate.model.read().as_ref().unwrap() != &current_cookie.model.unwrap()
-                {
-                    self.cookie_rotate(UselessReason::Null);
-                    return;
-                }
             }
         }
 
-        let res = self.try_bootstrap().await;
-        if let Err(ClewdrError::JsError(v)) = res {
+        let res = self.try_bootstrap(session).await;
+        if let Err(ClewdrError::JsError(v, _)) = res {
             if Some(json!("Invalid authorization")) == v.message {
                 error!("{}", "Invalid authorization".red());
-                self.cookie_rotate(UselessReason::Invalid);
+                self.rotate_locked(UselessReason::Invalid, session);
             }
         }
     }
 
-    async fn try_bootstrap(&self) -> Result<(), ClewdrError> {
+    async fn try_bootstrap(&self, session: &OwnedRwLockWriteGuard<()>) -> Result<(), ClewdrError> {
         let istate = self.0.clone();
         let config = istate.config.read().clone();
         if !config.cookie.validate() {
@@ -45,17 +45,16 @@ impl AppState {
             return Err(ClewdrError::InvalidAuth);
         }
         self.update_cookies(&config.cookie.to_string());
-        let end_point = config.endpoint("api/bootstrap");
-        let res = SUPER_CLIENT
-            .get(end_point.clone())
-            .append_headers("", self.header_cookie()?)
-            .send()
+        let endpoint = config.endpoint("");
+        let res = istate
+            .upstream
+            .bootstrap(&endpoint, &self.header_cookie()?)
             .await?;
-        let res = check_res_err(res).await?;
+        let res = check_res_err(res, &self.0.metrics).await?;
         let bootstrap = res.json::<Value>().await?;
         if bootstrap["account"].is_null() {
             println!("{}", "Null Error, Useless Cookie".red());
-            self.cookie_rotate(UselessReason::Null);
+            self.rotate_locked(UselessReason::Null, session);
             return Err(ClewdrError::InvalidAuth);
         }
         let memberships = bootstrap["account"]["memberships"]
@@ -102,9 +101,7 @@ impl AppState {
             // drop lock by using a new scope
             let mut config = istate.config.write();
             if let Some(cookie_model) = &cookie_model {
-                if !MODELS.contains(&cookie_model.as_str())
-                    && !config.unknown_models.contains(cookie_model)
-                {
+                if !is_known(cookie_model) && !config.unknown_models.contains(cookie_model) {
                     config.unknown_models.push(cookie_model.clone());
                     config.save().unwrap_or_else(|e| {
                         println!("Failed to save config: {}", e);
@@ -122,13 +119,6 @@ impl AppState {
                 }
             }
         }
-        if is_pro.is_none()
-            && istate.model.read().is_some()
-            && istate.model.read().as_ref() != cookie_model.as_ref()
-        {
-            self.cookie_rotate(UselessReason::Null);
-            return Err(ClewdrError::InvalidAuth);
-        }
         let config = istate.config.read().clone();
         let index = if config.index() < 0 {
             "".to_string()
@@ -187,23 +177,19 @@ impl AppState {
                 UselessReason::Overlap
             };
             println!("Cookie is useless, reason: {}", reason.to_string().red());
-            self.cookie_rotate(reason);
+            self.rotate_locked(reason, session);
             return Err(ClewdrError::InvalidAuth);
         } else {
             istate.uuid_org_array.write().push(uuid.to_string());
         }
 
         // Bootstrap complete
-        let rproxy = config.rproxy.clone();
-        let end_point = if rproxy.is_empty() { ENDPOINT } else { &rproxy };
-        let end_point = format!("{}/api/organizations", end_point);
-        let res = SUPER_CLIENT
-            .get(end_point.clone())
-            .append_headers("", self.header_cookie()?)
-            .send()
+        let res = istate
+            .upstream
+            .organizations(&endpoint, &self.header_cookie()?)
             .await?;
         self.update_cookie_from_res(&res);
-        let res = check_res_err(res).await?;
+        let res = check_res_err(res, &self.0.metrics).await?;
         let ret_json = res.json::<Value>().await?;
         // print bootstrap to out.json, if it exists, overwrite it
         let acc_info = ret_json
@@ -261,14 +247,14 @@ impl AppState {
                     "{}",
                     "Your account is banned, please use another account.".red()
                 );
-                self.cookie_rotate(UselessReason::Banned);
+                self.rotate_locked(UselessReason::Banned, session);
                 return Err(ClewdrError::InvalidAuth);
             } else {
                 // Restricted
                 println!("{}", "Your account is restricted.".red());
                 if self.0.config.read().settings.skip_restricted && restrict_until > 0 {
                     warn!("skip_restricted is enabled, skipping...");
-                    self.cookie_rotate(UselessReason::Temporary(restrict_until));
+                    self.rotate_locked(UselessReason::Temporary(restrict_until), session);
                     return Ok(());
                 }
             }
@@ -278,8 +264,6 @@ impl AppState {
             .and_then(|a| a.as_bool())
             .unwrap_or(false);
         if preview_feature_uses_artifacts != self.0.config.read().settings.artifacts {
-            let endpoint = self.0.config.read().endpoint("api/account");
-            let endpoint = format!("{}/api/account", endpoint);
             let mut account_settings = bootstrap
                 .pointer("/account/settings")
                 .and_then(|a| a.as_object())
@@ -292,16 +276,15 @@ impl AppState {
             let body = json!({
                 "settings": account_settings,
             });
-            let res = SUPER_CLIENT
-                .post(endpoint.clone())
-                .append_headers("", self.header_cookie()?)
-                .json(&body)
-                .send()
+            let res = istate
+                .upstream
+                .update_account(&endpoint, &self.header_cookie()?, &body)
                 .await?;
 
             self.update_cookie_from_res(&res);
-            check_res_err(res).await?;
+            check_res_err(res, &self.0.metrics).await?;
         }
+        self.set_bootstrapped();
         Ok(())
     }
 }
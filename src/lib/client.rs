@@ -3,14 +3,20 @@ use futures::future::join_all;
 use rquest::{
     Client, ClientBuilder, RequestBuilder,
     header::{COOKIE, ORIGIN, REFERER},
-    multipart::{Form, Part},
 };
 use rquest_util::Emulation;
 use serde_json::Value;
 use std::sync::LazyLock;
 use tracing::warn;
 
-use crate::{types::message::ImageSource, utils::ENDPOINT};
+use crate::{
+    config::ImageSettings,
+    error::{ClewdrError, check_res_err},
+    images,
+    state::AppState,
+    types::message::ImageSource,
+    utils::ENDPOINT,
+};
 
 pub static NORMAL_CLIENT: LazyLock<Client> = LazyLock::new(|| {
     ClientBuilder::new()
@@ -45,73 +51,141 @@ fn header_ref<S: AsRef<str>>(ref_path: S) -> String {
     }
 }
 
-pub async fn upload_images(
-    imgs: Vec<ImageSource>,
-    cookies: String,
-    uuid_org: String,
-) -> Vec<String> {
-    // upload images
-    let fut = imgs
-        .into_iter()
-        .map_while(|img| {
-            if img.type_ != "base64" {
-                warn!("Image type is not base64");
-                return None;
-            }
-            let bytes = BASE64_STANDARD
-                .decode(img.data.as_bytes())
-                .inspect_err(|e| {
-                    warn!("Failed to decode image: {:?}", e);
-                })
-                .ok()?;
-            let file_name = match img.media_type.as_str() {
-                "image/png" => "image.png",
-                "image/jpeg" => "image.jpg",
-                "image/gif" => "image.gif",
-                "image/webp" => "image.webp",
-                "application/pdf" => "document.pdf",
-                _ => "file",
-            };
-            let part = Part::bytes(bytes).file_name(file_name);
-            let form = Form::new().part("file", part);
+/// A file from the request to upload to claude.ai
+#[derive(Debug, Clone)]
+pub struct Upload {
+    /// How failures name the file, e.g. `image 2`
+    pub label: String,
+    pub source: ImageSource,
+    /// File name of a document, which must be a PDF, `None` for images
+    pdf_name: Option<String>,
+}
 
-            let endpoint = format!("https://claude.ai/api/{}/upload", uuid_org);
-            Some(
-                SUPER_CLIENT
-                    .post(endpoint)
-                    .append_headers("new", cookies.as_str())
-                    .header_append("anthropic-client-platform", "web_claude_ai")
-                    .multipart(form)
-                    .send(),
-            )
-        })
-        .collect::<Vec<_>>();
+impl Upload {
+    /// The `n`th image of the request, counted from 1
+    pub fn image(n: usize, source: ImageSource) -> Self {
+        Self {
+            label: format!("image {}", n),
+            source,
+            pdf_name: None,
+        }
+    }
 
-    // get upload responses
-    let fut = join_all(fut)
-        .await
-        .into_iter()
-        .map_while(|r| {
-            r.inspect_err(|e| {
-                warn!("Failed to upload image: {:?}", e);
-            })
-            .ok()
-        })
-        .map(|r| async {
-            let json = r
-                .json::<Value>()
-                .await
-                .inspect_err(|e| {
-                    warn!("Failed to parse image response: {:?}", e);
-                })
-                .ok()?;
-            Some(json["file_uuid"].as_str()?.to_string())
-        })
-        .collect::<Vec<_>>();
+    /// The `n`th document of the request, a base64 PDF, named after its `title`
+    pub fn document(n: usize, title: Option<&str>, media_type: String, data: String) -> Self {
+        let label = match title {
+            Some(title) => format!("document {} ({})", n, title),
+            None => format!("document {}", n),
+        };
+        let file_name = match title.map(|t| t.replace(['/', '\\', '"'], "_")) {
+            Some(title) if title.ends_with(".pdf") => title,
+            Some(title) => format!("{}.pdf", title),
+            None => "document.pdf".to_string(),
+        };
+        Self {
+            label,
+            source: ImageSource {
+                type_: "base64".to_string(),
+                media_type,
+                data,
+                url: None,
+            },
+            pdf_name: Some(file_name),
+        }
+    }
 
-    join_all(fut)
-        .await
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>()
+    /// File name and bytes to upload, or why the file can't be sent
+    ///
+    /// Images are fetched when they come from a URL, then checked and fitted
+    /// to `settings` on the blocking pool, see `images::prepare`.
+    async fn load(&self, settings: &ImageSettings) -> Result<(String, Vec<u8>), String> {
+        let bytes = match (self.source.type_.as_str(), &self.source.url) {
+            ("base64", _) => BASE64_STANDARD
+                .decode(self.source.data.as_bytes())
+                .map_err(|e| format!("invalid base64 data: {}", e))?,
+            ("url", Some(url)) if self.pdf_name.is_none() => images::fetch(url, settings).await?,
+            (type_, _) => return Err(format!("unsupported source type {}", type_)),
+        };
+        let Some(pdf_name) = &self.pdf_name else {
+            let (settings, media_type) = (settings.clone(), self.source.media_type.clone());
+            let (bytes, media_type) =
+                tokio::task::spawn_blocking(move || images::prepare(bytes, &media_type, &settings))
+                    .await
+                    .map_err(|e| format!("failed to prepare image: {}", e))??;
+            return Ok((images::file_name(media_type).to_string(), bytes));
+        };
+        if self.source.media_type != "application/pdf" {
+            return Err(format!(
+                "unsupported media type {}, only application/pdf is",
+                self.source.media_type
+            ));
+        }
+        if !bytes.starts_with(b"%PDF-") {
+            return Err("data is not a PDF".to_string());
+        }
+        Ok((pdf_name.clone(), bytes))
+    }
+}
+
+impl AppState {
+    /// Upload files to the current organization, returns the file uuids in order
+    ///
+    /// Every file is loaded first, and any that can't be fails the request as
+    /// invalid before anything is uploaded. Then every upload claude.ai rejects
+    /// is reported. Failures are listed in request order.
+    pub async fn upload_files(&self, uploads: Vec<Upload>) -> Result<Vec<String>, ClewdrError> {
+        if uploads.is_empty() {
+            return Ok(vec![]);
+        }
+        let settings = self.0.config.read().images.clone();
+        let loaded = join_all(uploads.iter().map(|u| u.load(&settings))).await;
+        let mut files = vec![];
+        let mut invalid = vec![];
+        for (upload, res) in uploads.iter().zip(loaded) {
+            match res {
+                Ok(file) => files.push(file),
+                Err(e) => invalid.push(format!("{}: {}", upload.label, e)),
+            }
+        }
+        if !invalid.is_empty() {
+            return Err(ClewdrError::InvalidRequest(invalid.join("; ")));
+        }
+        let cookies = self.header_cookie()?;
+        let endpoint = self.0.config.read().endpoint("");
+        let uuid_org = self.0.uuid_org.read().clone();
+        let upstream = &self.0.upstream;
+        let metrics = &self.0.metrics;
+        let (endpoint, cookies, uuid_org) = (&endpoint, &cookies, &uuid_org);
+        let fut = files.into_iter().map(|(file_name, bytes)| async move {
+            let res = upstream
+                .upload(endpoint, cookies, uuid_org, &file_name, bytes)
+                .await?;
+            let json = check_res_err(res, metrics).await?.json::<Value>().await?;
+            json["file_uuid"]
+                .as_str()
+                .map(|u| u.to_string())
+                .ok_or(ClewdrError::UnexpectedNone)
+        });
+        let mut files = vec![];
+        let mut failed = vec![];
+        for (upload, res) in uploads.iter().zip(join_all(fut).await) {
+            let result = if res.is_ok() { "ok" } else { "error" };
+            self.0
+                .metrics
+                .image_uploads
+                .with_label_values(&[result])
+                .inc();
+            match res {
+                Ok(file) => files.push(file),
+                Err(e) => {
+                    warn!("Failed to upload {}: {}", upload.label, e);
+                    failed.push(format!("{}: {}", upload.label, e));
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(ClewdrError::UploadFailed(failed.join("; ")));
+        }
+        Ok(files)
+    }
 }
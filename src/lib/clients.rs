@@ -0,0 +1,133 @@
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::{config::ClientInfo, state::AppState, usage::ANONYMOUS_CLIENT};
+
+/// Window of the `rpm` limit
+pub const RPM_WINDOW: Duration = Duration::from_secs(60);
+
+/// Error response in the format of the Anthropic API
+pub fn anthropic_error(status: StatusCode, kind: &str, message: &str) -> Response {
+    (
+        status,
+        Json(json!({
+            "type": "error",
+            "error": {
+                "type": kind,
+                "message": message,
+            },
+        })),
+    )
+        .into_response()
+}
+
+/// Name to log and account a request under
+pub fn client_name(client: Option<&ClientInfo>) -> &str {
+    client.map_or(ANONYMOUS_CLIENT, |c| c.name.as_str())
+}
+
+/// Recent request times per client, for the `rpm` limit
+#[derive(Default)]
+pub struct RequestWindows(Mutex<HashMap<String, VecDeque<Instant>>>);
+
+impl RequestWindows {
+    /// Count a request unless `limit` were exceeded, else return the wait time
+    fn acquire(&self, name: &str, limit: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.0.lock();
+        let window = windows.entry(name.to_string()).or_default();
+        while window
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RPM_WINDOW)
+        {
+            window.pop_front();
+        }
+        if window.len() >= limit as usize {
+            let oldest = window.front().copied().unwrap_or(now);
+            return Err(RPM_WINDOW.saturating_sub(now.duration_since(oldest)));
+        }
+        window.push_back(now);
+        Ok(())
+    }
+}
+
+impl AppState {
+    /// Client configured with `key`
+    pub fn find_client(&self, key: &str) -> Option<ClientInfo> {
+        let config = self.0.config.read();
+        config.clients.iter().find(|c| c.key == key).cloned()
+    }
+
+    /// Check that `client` may use `model`
+    pub fn check_client_model(&self, client: &ClientInfo, model: &str) -> Result<(), Response> {
+        if client.models.is_empty() || client.models.iter().any(|m| m == model) {
+            return Ok(());
+        }
+        warn!("Client {} is not allowed to use {}", client.name, model);
+        Err(anthropic_error(
+            StatusCode::FORBIDDEN,
+            "permission_error",
+            &format!("Model {} is not allowed for this API key", model),
+        ))
+    }
+
+    /// Check the model and quotas of `client`, counting the request if admitted
+    pub fn admit_client(&self, client: &ClientInfo, model: &str) -> Result<(), Response> {
+        self.check_client_model(client, model)?;
+        if client.daily_tokens > 0 {
+            let used = self.0.usage.read().tokens_today(&client.name);
+            if used >= client.daily_tokens {
+                warn!("Client {} reached its daily token limit", client.name);
+                let tomorrow = chrono::Utc::now()
+                    .date_naive()
+                    .succ_opt()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .map_or(0, |t| t.and_utc().timestamp());
+                let wait = tomorrow - chrono::Utc::now().timestamp();
+                return Err(rate_limited(
+                    &format!(
+                        "Daily token limit of {} reached for this API key",
+                        client.daily_tokens
+                    ),
+                    wait.max(1) as u64,
+                ));
+            }
+        }
+        if client.rpm > 0 {
+            self.0
+                .request_windows
+                .acquire(&client.name, client.rpm)
+                .map_err(|wait| {
+                    warn!(
+                        "Client {} exceeded {} requests per minute",
+                        client.name, client.rpm
+                    );
+                    rate_limited(
+                        &format!(
+                            "Rate limit of {} requests per minute exceeded for this API key",
+                            client.rpm
+                        ),
+                        wait.as_secs().max(1),
+                    )
+                })?;
+        }
+        Ok(())
+    }
+}
+
+fn rate_limited(message: &str, retry_after: u64) -> Response {
+    let mut res = anthropic_error(StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", message);
+    res.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    res
+}
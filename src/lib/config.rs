@@ -1,16 +1,25 @@
 use colored::Colorize;
 use rand::{Rng, rng};
 use serde::{Deserialize, Serialize};
-use std::fmt::{Debug, Display};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{Debug, Display},
+    path::PathBuf,
+};
 use tracing::{error, info, warn};
 
 use crate::{
     Args,
+    api::Backend,
     error::ClewdrError,
+    migrate::{self, CURRENT_CONFIG_VERSION},
+    persist::{self, Persister},
+    usage::ANONYMOUS_CLIENT,
     utils::{ENDPOINT, cwd_or_exec},
 };
 
 pub const CONFIG_NAME: &str = "config.toml";
+pub const DEFAULT_BACKUP_COUNT: usize = 3;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum UselessReason {
@@ -56,20 +65,132 @@ pub struct CookieInfo {
     #[serde(deserialize_with = "validate_reset")]
     #[serde(default)]
     pub reset_time: Option<i64>,
+    /// Group for `routes`, rotation stays within it
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiKeyInfo {
+    pub key: String,
+    #[serde(default)]
+    pub reset_time: Option<i64>,
+}
+
+/// A team member or app with its own key and limits
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ClientInfo {
+    pub name: String,
+    pub key: String,
+    /// Models this client may request, any when empty
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Requests per minute, unlimited when 0
+    #[serde(default)]
+    pub rpm: u32,
+    /// Input plus output tokens per UTC day, unlimited when 0
+    #[serde(default)]
+    pub daily_tokens: u64,
+    /// Cookie group that serves this client, before any of `routes`
+    #[serde(default)]
+    pub cookie_group: Option<String>,
+}
+
+/// What happens to thinking blocks on the claude.ai path
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ThinkingMode {
+    /// `thinking` content blocks, as the Messages API sends them
+    #[default]
+    Keep,
+    /// Removed from the response
+    Strip,
+    /// Sent as text between `<thinking>` tags
+    Inline,
+}
+
+/// Sends matching requests to the cookies of a group
+///
+/// Every condition that is set must match, the first matching rule wins.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct RouteRule {
+    /// Model names, a trailing `*` matches any suffix
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Client names from `clients`, `anonymous` for the rest
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// Request headers and their required values
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Group the cookies must be in, any when unset
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Tags the cookies must all have
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    // Layout version, see `migrate`
+    #[serde(default)]
+    pub config_version: u32,
+
+    /// File this config is saved to and reloaded from, kept in memory only when unset
+    #[serde(skip)]
+    file: Option<PathBuf>,
+    /// Writes this config and the files next to it
+    #[serde(skip)]
+    persister: Persister,
+
     // Cookie configurations
     pub cookie: Cookie,
     cookie_array: Vec<CookieInfo>,
     pub wasted_cookie: Vec<UselessCookie>,
     pub unknown_models: Vec<String>,
 
+    // Official API keys, used for `api_models` or on request
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyInfo>,
+    #[serde(default)]
+    pub wasted_api_keys: Vec<String>,
+    #[serde(default)]
+    pub api_models: Vec<String>,
+
+    // Failover chain, tried in order when a backend fails before streaming
+    #[serde(default)]
+    pub fallback: Vec<Backend>,
+    #[serde(default)]
+    pub fallback_rproxy: String,
+    #[serde(default)]
+    pub fallback_rproxy_key: String,
+
+    // Client keys with their own quotas, accepted besides `proxy_password`
+    #[serde(default)]
+    pub clients: Vec<ClientInfo>,
+    // Cookie group routing, see `routing`
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    // Hard cap on `max_tokens` of every request, 0 for none
+    #[serde(default)]
+    pub max_tokens_cap: u32,
+    // Thinking blocks from claude.ai, for clients that don't support them
+    #[serde(default)]
+    pub thinking_mode: ThinkingMode,
+    // Alternative model names, resolved before a request is served
+    #[serde(default)]
+    pub model_aliases: BTreeMap<String, String>,
+
     // Network settings
     pub cookie_counter: u32,
     cookie_index: i32,
     pub proxy_password: String,
+    #[serde(default)]
+    pub admin_password: String,
     ip: String,
     port: u16,
     pub local_tunnel: bool,
@@ -91,12 +212,19 @@ pub struct Config {
     pub prompt_experiment_next: String,
     pub user_real_roles: bool,
 
+    // Persistence settings
+    #[serde(default = "default_backup_count")]
+    pub backup_count: usize,
+
     // Nested settings section
     #[serde(default)]
     pub settings: Settings,
+    // Image fetching and preprocessing
+    #[serde(default)]
+    pub images: ImageSettings,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Settings {
     pub renew_always: bool,
     pub prompt_experiments: bool,
@@ -110,6 +238,33 @@ pub struct Settings {
     pub artifacts: bool,
 }
 
+/// Limits for images sent to claude.ai
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ImageSettings {
+    /// Seconds to wait for an image fetched from a URL
+    pub fetch_timeout: u64,
+    /// Largest image fetched from a URL, in bytes
+    pub fetch_max_bytes: u64,
+    /// Hosts fetched from even when they resolve to a loopback, link-local or
+    /// private address
+    pub fetch_allow_hosts: Vec<String>,
+    /// Hosts never fetched from
+    pub fetch_deny_hosts: Vec<String>,
+    /// Images with a longer side are downscaled to it, 0 to keep the size
+    pub max_dimension: u32,
+    /// Images larger than this are recompressed as JPEG, 0 to keep them
+    pub max_bytes: u64,
+    /// Images with a longer side are refused rather than decoded
+    pub decode_max_dimension: u32,
+    /// Memory a decoded image may take, in bytes
+    pub decode_max_bytes: u64,
+}
+
+fn default_backup_count() -> usize {
+    DEFAULT_BACKUP_COUNT
+}
+
 const PLACEHOLDER_COOKIE: &str = "sk-ant-REDACTED";
 
 fn validate_reset<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
@@ -141,6 +296,8 @@ impl CookieInfo {
             cookie: Cookie::from(cookie),
             model: model.map(|m| m.to_string()),
             reset_time,
+            group: None,
+            tags: vec![],
         }
     }
     pub fn is_pro(&self) -> bool {
@@ -150,7 +307,7 @@ impl CookieInfo {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Cookie {
     inner: String,
 }
@@ -222,6 +379,9 @@ impl<'de> Deserialize<'de> for Cookie {
 impl Default for Config {
     fn default() -> Self {
         Self {
+            config_version: CURRENT_CONFIG_VERSION,
+            file: None,
+            persister: Persister::default(),
             cookie: Cookie::from(PLACEHOLDER_COOKIE),
             cookie_array: vec![
                 CookieInfo::new(PLACEHOLDER_COOKIE, None, None),
@@ -229,9 +389,21 @@ impl Default for Config {
             ],
             wasted_cookie: Vec::new(),
             unknown_models: Vec::new(),
+            api_keys: Vec::new(),
+            wasted_api_keys: Vec::new(),
+            api_models: Vec::new(),
+            fallback: Vec::new(),
+            fallback_rproxy: String::new(),
+            fallback_rproxy_key: String::new(),
+            clients: Vec::new(),
+            routes: Vec::new(),
+            max_tokens_cap: 0,
+            thinking_mode: ThinkingMode::default(),
+            model_aliases: BTreeMap::new(),
             cookie_counter: 3,
             cookie_index: -1,
             proxy_password: String::new(),
+            admin_password: String::new(),
             ip: "127.0.0.1".to_string(),
             port: 8484,
             local_tunnel: false,
@@ -244,7 +416,9 @@ impl Default for Config {
             prompt_experiment_first: String::new(),
             prompt_experiment_next: String::new(),
             settings: Settings::default(),
+            images: ImageSettings::default(),
             user_real_roles: false,
+            backup_count: DEFAULT_BACKUP_COUNT,
         }
     }
 }
@@ -266,50 +440,231 @@ impl Default for Settings {
     }
 }
 
+impl Default for ImageSettings {
+    fn default() -> Self {
+        Self {
+            fetch_timeout: 10,
+            fetch_max_bytes: 10 * 1024 * 1024,
+            fetch_allow_hosts: vec![],
+            fetch_deny_hosts: vec![],
+            max_dimension: 0,
+            max_bytes: 0,
+            decode_max_dimension: 16384,
+            decode_max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
 impl Config {
     pub fn load() -> Result<Self, ClewdrError> {
-        let file_string = std::fs::read_to_string(CONFIG_NAME).or_else(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                let exec_path = std::env::current_exe()?;
-                let config_dir = exec_path.parent().ok_or(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "Failed to get parent directory",
-                ))?;
-                let config_path = config_dir.join(CONFIG_NAME);
-                std::fs::read_to_string(config_path)
-            } else {
-                Err(e)
-            }
-        });
+        // refuse to run two instances against the same config file
+        let config_path = Self::config_path()?;
+        persist::lock_instance(&config_path)?;
+        let args: Args = clap::Parser::parse();
+        if let Some(clewd_path) = &args.import_clewd {
+            let mut config = migrate::import_clewd(&std::fs::read_to_string(clewd_path)?)?
+                .with_file(&config_path);
+            println!("Imported clewd config from {}", clewd_path.green());
+            config.load_from_arg_file(&args);
+            config = config.validate();
+            config.save()?;
+            config.persister.flush();
+            return Ok(config);
+        }
+        // read the file that is locked and saved to
+        let file_string = std::fs::read_to_string(&config_path);
         match file_string {
             Ok(file_string) => {
-                let mut config: Config = toml::de::from_str(&file_string)?;
-                config.load_from_arg_file();
+                let mut config = Config::parse(&file_string)?.with_file(&config_path);
+                config.load_from_arg_file(&args);
                 config = config.validate();
                 config.save()?;
+                config.persister.flush();
                 Ok(config)
             }
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                let exec_path = std::env::current_exe()?;
-                let config_dir = exec_path.parent().ok_or(ClewdrError::PathNotFound(
-                    "Failed to get parent directory".to_string(),
-                ))?;
-                let mut default_config = Config::default();
-                let canonical_path = std::fs::canonicalize(config_dir)?;
+                let mut default_config = Config::default().with_file(&config_path);
                 println!(
-                    "Default config file created at {}/config.toml",
-                    canonical_path.display()
+                    "Default config file created at {}",
+                    std::path::absolute(&config_path)?.display()
                 );
                 println!("{}", "SET YOUR COOKIE HERE".green());
-                default_config.load_from_arg_file();
+                default_config.load_from_arg_file(&args);
                 default_config = default_config.validate();
                 default_config.save()?;
+                default_config.persister.flush();
                 Ok(default_config)
             }
             Err(e) => Err(e.into()),
         }
     }
 
+    /// Parse and validate config file content without touching the running config
+    ///
+    /// Unlike [`Config::load`] this neither creates a default file nor reads
+    /// cookies from the command line, so it is safe to call while serving.
+    pub fn parse(file_string: &str) -> Result<Self, ClewdrError> {
+        let mut table: toml::Table = toml::de::from_str(file_string)?;
+        migrate::migrate(&mut table);
+        migrate::warn_unknown_keys(&table);
+        let config: Config = table.try_into()?;
+        let config = config.validate();
+        config.check_clients()?;
+        Ok(config)
+    }
+
+    /// Reject client names that would share quotas, usage and metrics
+    ///
+    /// Rate windows, daily usage and metric labels are all keyed by name, and
+    /// requests without a client key are counted as [`ANONYMOUS_CLIENT`].
+    fn check_clients(&self) -> Result<(), ClewdrError> {
+        let mut names = HashSet::new();
+        for client in &self.clients {
+            if client.name == ANONYMOUS_CLIENT {
+                return Err(ClewdrError::PathNotFound(format!(
+                    "client name {} is reserved",
+                    ANONYMOUS_CLIENT
+                )));
+            }
+            if !names.insert(client.name.as_str()) {
+                return Err(ClewdrError::PathNotFound(format!(
+                    "client name {} is used more than once",
+                    client.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge a freshly read config into the running one
+    ///
+    /// New cookies are appended to the pool, cookies missing from the file are
+    /// removed, edited `model`, `group` and `tags` are taken over, and settings
+    /// are replaced. The current cookie and the wasted cookies are left
+    /// untouched so in-flight requests keep working. Returns a description of
+    /// each change.
+    pub fn merge_reloaded(&mut self, new: Config) -> Vec<String> {
+        let mut changes = vec![];
+        let current = usize::try_from(self.cookie_index)
+            .ok()
+            .and_then(|i| self.cookie_array.get(i))
+            .map(|c| c.cookie.clone());
+        let in_file = |c: &CookieInfo| new.cookie_array.iter().find(|n| n.cookie == c.cookie);
+        let before = self.cookie_array.len();
+        self.cookie_array
+            .retain(|c| current.as_ref() == Some(&c.cookie) || in_file(c).is_some());
+        let removed = before - self.cookie_array.len();
+        let mut edited = 0;
+        for info in &mut self.cookie_array {
+            let Some(n) = in_file(info).filter(|_| current.as_ref() != Some(&info.cookie)) else {
+                continue;
+            };
+            if (&info.model, &info.group, &info.tags) != (&n.model, &n.group, &n.tags) {
+                info.model = n.model.clone();
+                info.group = n.group.clone();
+                info.tags = n.tags.clone();
+                edited += 1;
+            }
+        }
+        if removed > 0 {
+            changes.push(format!("{} cookie(s) removed", removed));
+        }
+        if edited > 0 {
+            changes.push(format!("{} cookie(s) edited", edited));
+        }
+        if let Some(current) = &current {
+            let index = self.cookie_array.iter().position(|c| &c.cookie == current);
+            self.cookie_index = index.map_or(-1, |i| i as i32);
+        } else if self.cookie_array.is_empty() {
+            self.cookie_index = -1;
+        }
+        let was_empty = self.cookie_array.is_empty();
+        let mut added = 0;
+        for info in new.cookie_array {
+            if self.cookie_array.iter().any(|c| c.cookie == info.cookie)
+                || self.wasted_cookie.iter().any(|c| c.cookie == info.cookie)
+            {
+                continue;
+            }
+            self.cookie_array.push(info);
+            added += 1;
+        }
+        if added > 0 {
+            changes.push(format!("{} new cookie(s)", added));
+            if was_empty {
+                self.cookie_index = 0;
+            }
+        }
+        let keys_before = self.api_keys.len();
+        self.api_keys
+            .retain(|k| new.api_keys.iter().any(|n| n.key == k.key));
+        if self.api_keys.len() < keys_before {
+            changes.push(format!(
+                "{} API key(s) removed",
+                keys_before - self.api_keys.len()
+            ));
+        }
+        let mut added_keys = 0;
+        for info in new.api_keys {
+            if self.api_keys.iter().any(|k| k.key == info.key)
+                || self.wasted_api_keys.contains(&info.key)
+            {
+                continue;
+            }
+            self.api_keys.push(info);
+            added_keys += 1;
+        }
+        if added_keys > 0 {
+            changes.push(format!("{} new API key(s)", added_keys));
+        }
+        if self.ip != new.ip || self.port != new.port {
+            let address = format!("{}:{}", new.ip, new.port);
+            warn!("Address changed to {}, restart to apply", address.yellow());
+        }
+        if self.cookie_index < 0 && self.cookie != new.cookie {
+            self.cookie = new.cookie;
+            changes.push("cookie".to_string());
+        }
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if self.$field != new.$field {
+                        self.$field = new.$field;
+                        changes.push(stringify!($field).to_string());
+                    }
+                )*
+            };
+        }
+        apply!(
+            cookie_counter,
+            proxy_password,
+            admin_password,
+            local_tunnel,
+            buffer_size,
+            system_interval,
+            rproxy,
+            api_rproxy,
+            api_models,
+            fallback,
+            fallback_rproxy,
+            fallback_rproxy_key,
+            clients,
+            routes,
+            max_tokens_cap,
+            thinking_mode,
+            model_aliases,
+            placeholder_token,
+            placeholder_byte,
+            prompt_experiment_first,
+            prompt_experiment_next,
+            user_real_roles,
+            backup_count,
+            settings,
+            images,
+        );
+        changes
+    }
+
     pub fn cookie_cleaner(&mut self, reason: UselessReason) {
         if let UselessReason::Temporary(_) = reason {
             warn!("Temporary useless cookie, not cleaning");
@@ -349,29 +704,67 @@ impl Config {
         format!("{}:{}", self.ip, self.port)
     }
 
-    pub fn save(&self) -> Result<(), ClewdrError> {
-        let existing = cwd_or_exec();
-        if let Ok(existing) = existing {
-            let config_path = existing.join(CONFIG_NAME);
-            // overwrite the file if it exists
-            std::fs::write(config_path, toml::ser::to_string(self)?)?;
-            return Ok(());
+    /// Path of the config file, in cwd or next to the executable
+    pub fn config_path() -> Result<PathBuf, ClewdrError> {
+        if let Ok(existing) = cwd_or_exec() {
+            return Ok(existing.join(CONFIG_NAME));
         }
         let exec_path = std::env::current_exe()?;
         let config_dir = exec_path.parent().ok_or(ClewdrError::PathNotFound(
             "Failed to get parent directory".to_string(),
         ))?;
-        // add file name to the path
         if !config_dir.exists() {
             std::fs::create_dir_all(config_dir)?;
         }
-        // Save the config to a file
-        let config_path = config_dir.join(CONFIG_NAME);
+        Ok(config_dir.join(CONFIG_NAME))
+    }
+
+    /// Save to and reload from `path`, without a file the config is kept in memory
+    pub fn with_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    /// Path this config is saved to and reloaded from
+    pub fn file_path(&self) -> Result<PathBuf, ClewdrError> {
+        self.file.clone().ok_or(ClewdrError::PathNotFound(
+            "Config is kept in memory, it has no file".to_string(),
+        ))
+    }
+
+    /// Whether the config is saved to a file, see [`Config::with_file`]
+    pub fn has_file(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Queue the config to be written to disk
+    ///
+    /// Writes are debounced and atomic, see [`Persister::schedule_write`].
+    /// Call [`Persister::flush`] before exiting to make sure nothing is lost.
+    /// A config without a file is not saved.
+    pub fn save(&self) -> Result<(), ClewdrError> {
+        let Some(config_path) = self.file.clone() else {
+            return Ok(());
+        };
         let config_string = toml::ser::to_string(self)?;
-        std::fs::write(config_path, config_string)?;
+        self.persister
+            .schedule_write(config_path, config_string, self.backup_count);
         Ok(())
     }
 
+    /// Writer of this config, shared by its clones
+    pub fn persister(&self) -> &Persister {
+        &self.persister
+    }
+
+    /// Cookie of the pool in use, `None` in single `cookie` mode
+    pub fn current_cookie(&self) -> Option<&Cookie> {
+        usize::try_from(self.cookie_index)
+            .ok()
+            .and_then(|i| self.cookie_array.get(i))
+            .map(|c| &c.cookie)
+    }
+
     pub fn current_cookie_info(&mut self) -> Option<&mut CookieInfo> {
         if self.cookie_index < 0 {
             return None;
@@ -407,14 +800,91 @@ impl Config {
         self.cookie_array.len()
     }
 
-    pub fn rotate_cookie(&mut self) {
+    pub fn cookie_array(&self) -> &[CookieInfo] {
+        &self.cookie_array
+    }
+
+    /// Pool entry of `cookie`, if it is in `cookie_array`
+    pub fn cookie_info_mut(&mut self, cookie: &Cookie) -> Option<&mut CookieInfo> {
+        self.cookie_array.iter_mut().find(|c| &c.cookie == cookie)
+    }
+
+    /// Add a cookie to the pool, returns false if it is already known
+    pub fn add_cookie(&mut self, info: CookieInfo) -> bool {
+        if self.cookie_array.iter().any(|c| c.cookie == info.cookie)
+            || self.wasted_cookie.iter().any(|c| c.cookie == info.cookie)
+        {
+            return false;
+        }
+        self.cookie_array.push(info);
+        if self.cookie_index < 0 {
+            self.cookie_index = 0;
+        }
+        true
+    }
+
+    /// Remove a cookie from the pool, keeping the index on the same cookie
+    /// when possible. Returns the removed cookie and whether it was the current one
+    pub fn remove_cookie(&mut self, cookie: &Cookie) -> Option<(CookieInfo, bool)> {
+        let pos = self.cookie_array.iter().position(|c| &c.cookie == cookie)? as i32;
+        let removed = self.cookie_array.remove(pos as usize);
+        let was_current = pos == self.cookie_index;
+        if was_current {
+            self.cookie.clear();
+        }
+        if pos < self.cookie_index || self.cookie_index == self.cookie_array.len() as i32 {
+            self.cookie_index -= 1;
+        }
+        Some((removed, was_current))
+    }
+
+    /// Remove a cookie from the wasted list
+    pub fn remove_wasted(&mut self, cookie: &Cookie) -> Option<UselessCookie> {
+        let pos = self
+            .wasted_cookie
+            .iter()
+            .position(|c| &c.cookie == cookie)?;
+        Some(self.wasted_cookie.remove(pos))
+    }
+
+    /// Move to the next cookie in the group of the current one
+    ///
+    /// Returns false if the group has no cookie left, see [`Config::rotate_cookie_in`].
+    pub fn rotate_cookie(&mut self) -> bool {
+        let group = self.current_cookie_info().and_then(|c| c.group.clone());
+        self.rotate_cookie_in(group.as_deref())
+    }
+
+    /// Move to the next cookie in `group`, `None` being the ungrouped cookies
+    ///
+    /// Returns false and keeps the index if no cookie of `group` is left.
+    pub fn rotate_cookie_in(&mut self, group: Option<&str>) -> bool {
         if self.cookie_array.is_empty() {
-            return;
+            return false;
+        }
+        let array_len = self.cookie_array.len() as i32;
+        let start = self.cookie_index;
+        let Some(next) = (1..=array_len)
+            .map(|i| (start + i).rem_euclid(array_len))
+            .find(|i| self.cookie_array[*i as usize].group.as_deref() == group)
+        else {
+            return false;
+        };
+        self.cookie_index = next;
+        warn!(
+            "Rotating cookie to index {}",
+            self.cookie_index.to_string().green()
+        );
+        true
+    }
+
+    /// Make the cookie at `index` current, returns false if there is none
+    pub fn set_index(&mut self, index: usize) -> bool {
+        if index >= self.cookie_array.len() {
+            return false;
         }
-        let array_len = self.cookie_array.len();
-        let index = &mut self.cookie_index;
-        *index = (*index + 1) % array_len as i32;
-        warn!("Rotating cookie to index {}", index.to_string().green());
+        self.cookie_index = index as i32;
+        true
     }
 
     fn validate(mut self) -> Self {
@@ -435,14 +905,26 @@ impl Config {
             .trim_end_matches('/')
             .trim_end_matches("/v1")
             .to_string();
+        self.fallback_rproxy = self
+            .fallback_rproxy
+            .trim()
+            .trim_end_matches('/')
+            .trim_end_matches("/v1")
+            .to_string();
+        self.clients.retain_mut(|c| {
+            c.key = c.key.trim().to_string();
+            if c.key.is_empty() {
+                warn!("Client {} has no key, ignoring it", c.name.yellow());
+            }
+            !c.key.is_empty()
+        });
         self.settings.padtxt = self.settings.padtxt.trim().to_string();
         self
     }
 
-    fn load_from_arg_file(&mut self) {
+    fn load_from_arg_file(&mut self, args: &Args) {
         // Load config from command line arguments
-        let args: Args = clap::Parser::parse();
-        let file = args.cookie_file;
+        let file = args.cookie_file.as_ref();
         let Some(file) = file else {
             return;
         };
@@ -470,6 +952,8 @@ impl Config {
                     cookie: c,
                     model: None,
                     reset_time: None,
+                    group: None,
+                    tags: vec![],
                 })
             })
             .collect::<Vec<_>>();
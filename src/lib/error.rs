@@ -3,6 +3,8 @@ use serde_json::{Value, json};
 use std::fmt::Display;
 use tracing::{error, warn};
 
+use crate::metrics::Metrics;
+
 #[derive(thiserror::Error, Debug)]
 pub enum ClewdrError {
     #[error("Invalid authorization")]
@@ -20,7 +22,7 @@ pub enum ClewdrError {
     #[error("UTF8 error: {0}")]
     UTF8Error(#[from] std::string::FromUtf8Error),
     #[error("JavaScript error {0}")]
-    JsError(JsError),
+    JsError(JsError, u16),
     #[error("Too many requests: {0}")]
     TooManyRequest(JsError, i64),
     #[error("Unexpected None")]
@@ -39,6 +41,66 @@ pub enum ClewdrError {
     TimestampError(i64),
     #[error("Wait for cookie rotation")]
     CookieRotating,
+    #[error("Config is locked by another instance: {0}")]
+    ConfigLocked(String),
+    #[error("Upstream returned {0}: {1}")]
+    UpstreamStatus(u16, String),
+    #[error("No cookie available: {0}")]
+    NoCookieAvailable(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Upload failed: {0}")]
+    UploadFailed(String),
+}
+
+impl ClewdrError {
+    /// Short label of the error, used to group error counts
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClewdrError::InvalidAuth => "invalid_auth",
+            ClewdrError::JsonError(_) => "json",
+            ClewdrError::TomlDeError(_) | ClewdrError::TomlSeError(_) => "toml",
+            ClewdrError::RegexError(_) => "regex",
+            ClewdrError::RquestError(_) => "network",
+            ClewdrError::UTF8Error(_) => "utf8",
+            ClewdrError::JsError(_, _) => "upstream",
+            ClewdrError::TooManyRequest(_, _) => "rate_limit",
+            ClewdrError::UnexpectedNone => "unexpected_none",
+            ClewdrError::NoValidKey => "no_valid_key",
+            ClewdrError::WrongCompletionFormat => "wrong_format",
+            ClewdrError::IoError(_) => "io",
+            ClewdrError::InvalidModel(_) => "invalid_model",
+            ClewdrError::PathNotFound(_) => "path_not_found",
+            ClewdrError::TimestampError(_) => "timestamp",
+            ClewdrError::CookieRotating => "cookie_rotating",
+            ClewdrError::ConfigLocked(_) => "config_locked",
+            ClewdrError::UpstreamStatus(_, _) => "upstream_status",
+            ClewdrError::NoCookieAvailable(_) => "no_cookie",
+            ClewdrError::InvalidRequest(_) => "invalid_request",
+            ClewdrError::UploadFailed(_) => "upload_failed",
+        }
+    }
+
+    /// Whether another backend may serve the request this error failed
+    ///
+    /// Only pool exhaustion, rate limits, upstream 5xx or overloaded errors and
+    /// connection errors qualify. Errors in the request itself would fail on
+    /// any backend.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClewdrError::TooManyRequest(_, _)
+            | ClewdrError::NoCookieAvailable(_)
+            | ClewdrError::CookieRotating
+            | ClewdrError::NoValidKey => true,
+            ClewdrError::UpstreamStatus(status, _) => *status >= 500,
+            ClewdrError::JsError(e, status) => {
+                *status >= 500
+                    || e.r#type.as_ref().and_then(|t| t.as_str()) == Some("overloaded_error")
+            }
+            ClewdrError::RquestError(e) => e.is_connect() || e.is_timeout(),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -58,7 +120,8 @@ impl Display for JsError {
     }
 }
 
-pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
+/// Turn an error response of claude.ai into an error, counting it in `metrics`
+pub async fn check_res_err(res: Response, metrics: &Metrics) -> Result<Response, ClewdrError> {
     let mut ret = JsError {
         name: "Error".to_string(),
         message: None,
@@ -68,6 +131,10 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
     };
     let status = res.status();
     if !status.is_success() {
+        metrics
+            .upstream_errors
+            .with_label_values(&[status.as_str()])
+            .inc();
         ret.message = Some(format!("Unexpected response code: {}", status).into());
         error!("Unexpected response code: {}", status);
     } else {
@@ -76,11 +143,14 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
     let json = res.text().await.inspect_err(|e| {
         error!("Failed to get response: {}\n", e);
     })?;
-    let json = serde_json::from_str::<Value>(&json).inspect_err(|e| {
-        error!("Failed to parse response: {}\n", e);
-    })?;
+    // an outage may answer with a page rather than JSON
+    let json = serde_json::from_str::<Value>(&json)
+        .inspect_err(|e| {
+            error!("Failed to parse response: {}\n", e);
+        })
+        .unwrap_or_default();
     let Some(err_api) = json.get("error") else {
-        return Err(ClewdrError::JsError(ret));
+        return Err(ClewdrError::JsError(ret, status.as_u16()));
     };
     ret.status = json.get("status").cloned();
     ret.planned = true.into();
@@ -108,7 +178,7 @@ pub async fn check_res_err(res: Response) -> Result<Response, ClewdrError> {
             return Err(ClewdrError::TooManyRequest(ret, time));
         }
     }
-    Err(ClewdrError::JsError(ret))
+    Err(ClewdrError::JsError(ret, status.as_u16()))
 }
 
 pub fn check_json_err(json: &Value) -> Value {
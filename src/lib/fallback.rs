@@ -0,0 +1,144 @@
+use axum::{http::HeaderMap, response::Response};
+use serde_json::Value;
+use std::time::Instant;
+use tracing::warn;
+
+use crate::{
+    api::{Backend, passthrough, requested_backend, select_backend},
+    client::NORMAL_CLIENT,
+    config::{ClientInfo, Config},
+    error::ClewdrError,
+    messages::ClientRequestBody,
+    models::metric_label,
+    routing::route_for,
+    state::AppState,
+    thinking::strip_placeholder_thinking,
+};
+
+/// Response header listing every backend tried, in order
+pub const ATTEMPTS_HEADER: &str = "x-clewdr-attempts";
+
+/// Backends to try for a request
+///
+/// A backend pinned with the request header is tried alone. Otherwise the
+/// selected backend goes first, followed by the entries after it in `fallback`.
+pub fn backend_chain(headers: &HeaderMap, model: &str, config: &Config) -> Vec<Backend> {
+    if let Some(backend) = requested_backend(headers) {
+        return vec![backend];
+    }
+    let first = select_backend(headers, model, config);
+    let rest = match config.fallback.iter().position(|b| *b == first) {
+        Some(pos) => &config.fallback[pos + 1..],
+        None => &config.fallback[..],
+    };
+    let mut chain = vec![first];
+    for backend in rest {
+        if !chain.contains(backend) {
+            chain.push(*backend);
+        }
+    }
+    chain
+}
+
+impl AppState {
+    /// Try each backend of the chain until one starts a response
+    ///
+    /// An attempt that fails before the first byte is streamed with a
+    /// retryable error moves on to the next backend, other errors are returned
+    /// as they are. Returns the backends tried, the last one served or failed.
+    /// `max_tokens` is lowered to `max_tokens_cap` first.
+    pub async fn dispatch_message(
+        &self,
+        mut body: Value,
+        headers: &HeaderMap,
+        client: Option<&ClientInfo>,
+    ) -> (Vec<Backend>, Result<Response, ClewdrError>) {
+        let model = body["model"].as_str().unwrap_or_default().to_string();
+        let (chain, route) = {
+            let config = self.0.config.read();
+            let cap = config.max_tokens_cap as u64;
+            if let Some(body) = body.as_object_mut().filter(|_| cap > 0) {
+                let max_tokens = body
+                    .get("max_tokens")
+                    .and_then(|m| m.as_u64())
+                    .map_or(cap, |m| m.min(cap));
+                body.insert("max_tokens".to_string(), max_tokens.into());
+            }
+            (
+                backend_chain(headers, &model, &config),
+                route_for(&config, headers, &model, client),
+            )
+        };
+        let mut attempts: Vec<Backend> = vec![];
+        let mut res = Err(ClewdrError::UnexpectedNone);
+        for backend in chain {
+            if let (Some(last), Err(e)) = (attempts.last(), &res) {
+                warn!(
+                    "Backend {} failed: {}, falling back to {}",
+                    last.as_str(),
+                    e,
+                    backend.as_str()
+                );
+            }
+            attempts.push(backend);
+            res = match backend {
+                Backend::Web => match serde_json::from_value::<ClientRequestBody>(body.clone()) {
+                    Ok(p) => self.try_message(p, client, route.as_ref()).await,
+                    Err(e) => Err(e.into()),
+                },
+                Backend::Api => self.try_api_message(body.clone(), headers).await,
+                Backend::Rproxy => self.try_rproxy_message(body.clone(), headers).await,
+            };
+            if !res.as_ref().is_err_and(|e| e.is_retryable()) {
+                break;
+            }
+        }
+        (attempts, res)
+    }
+
+    /// Forward a Messages API request body to `fallback_rproxy`
+    ///
+    /// Thinking blocks clewdr signed with a placeholder are removed first.
+    pub async fn try_rproxy_message(
+        &self,
+        mut body: Value,
+        headers: &HeaderMap,
+    ) -> Result<Response, ClewdrError> {
+        let start = Instant::now();
+        strip_placeholder_thinking(&mut body);
+        let (model, endpoint, key) = {
+            let config = self.0.config.read();
+            let model = metric_label(&config, body["model"].as_str().unwrap_or_default());
+            if config.fallback_rproxy.is_empty() {
+                return Err(ClewdrError::PathNotFound(
+                    "fallback_rproxy is not set".to_string(),
+                ));
+            }
+            (
+                model,
+                format!("{}/v1/messages", config.fallback_rproxy),
+                config.fallback_rproxy_key.clone(),
+            )
+        };
+        let mut req = NORMAL_CLIENT.post(endpoint).json(&body);
+        if !key.is_empty() {
+            req = req.header("x-api-key", key);
+        }
+        for name in ["anthropic-version", "anthropic-beta"] {
+            if let Some(value) = headers.get(name).and_then(|h| h.to_str().ok()) {
+                req = req.header(name, value);
+            }
+        }
+        let request_start = Instant::now();
+        let res = req.send().await?;
+        self.0
+            .metrics
+            .observe_upstream("rproxy_messages", request_start);
+        let status = res.status().as_u16();
+        if !res.status().is_success() {
+            let text = res.text().await.unwrap_or_default();
+            return Err(ClewdrError::UpstreamStatus(status, text));
+        }
+        passthrough(res, &self.0.metrics, &model, start)
+    }
+}
@@ -0,0 +1,139 @@
+use axum::{Json, extract::State, http::StatusCode};
+use serde_json::{Value, json};
+
+use crate::state::AppState;
+
+/// Liveness, the server is up and serving requests
+pub async fn api_healthz() -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+/// Readiness, a cookie is bootstrapped and usable right now
+pub async fn api_readyz(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let mut reasons = vec![];
+    if state.is_rotating() {
+        reasons.push("cookie rotation in progress");
+    }
+    if !state.is_bootstrapped() {
+        reasons.push("no cookie has completed bootstrap");
+    }
+    let config = state.0.config.read();
+    let now = chrono::Utc::now().timestamp();
+    let available = config
+        .cookie_array()
+        .iter()
+        .filter(|c| c.reset_time.is_none_or(|t| t <= now))
+        .count();
+    let usable = available > 0 || (config.cookie_array_len() == 0 && config.cookie.validate());
+    if !usable {
+        reasons.push("every cookie is wasted or temporarily limited");
+    }
+    let ready = reasons.is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "reasons": reasons,
+            "cookies": {
+                "available": available,
+                "total": config.cookie_array_len(),
+                "wasted": config.wasted_cookie.len(),
+            },
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, CookieInfo, UselessCookie, UselessReason};
+
+    const COOKIE: &str = "sk-ant-REDACTED";
+
+    /// Default config with `COOKIE` as its only pool cookie
+    fn config() -> Config {
+        let mut config = Config::default();
+        while let Some(cookie) = config.cookie_array().first().map(|c| c.cookie.clone()) {
+            config.remove_cookie(&cookie);
+        }
+        assert!(config.add_cookie(CookieInfo::new(COOKIE, None, None)));
+        config
+    }
+
+    /// State with one pool cookie, bootstrapped
+    fn ready_state() -> AppState {
+        let state = AppState::new(config());
+        state.set_bootstrapped();
+        state
+    }
+
+    async fn readyz(state: &AppState) -> (StatusCode, Value) {
+        let (status, Json(body)) = api_readyz(State(state.clone())).await;
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn ready_when_a_cookie_is_bootstrapped_and_usable() {
+        let (status, body) = readyz(&ready_state()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["reasons"], json!([]));
+        assert_eq!(body["cookies"]["available"], 1);
+    }
+
+    #[tokio::test]
+    async fn not_ready_before_bootstrap() {
+        let (status, body) = readyz(&AppState::new(config())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body["reasons"],
+            json!(["no cookie has completed bootstrap"])
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_rotating() {
+        let state = ready_state();
+        state.set_rotating(true);
+        let (status, body) = readyz(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reasons"], json!(["cookie rotation in progress"]));
+    }
+
+    #[tokio::test]
+    async fn not_ready_without_a_usable_cookie() {
+        let unusable = json!(["every cookie is wasted or temporarily limited"]);
+        let state = ready_state();
+        let later = chrono::Utc::now().timestamp() + 3600;
+        state
+            .0
+            .config
+            .write()
+            .cookie_info_mut(&COOKIE.into())
+            .unwrap()
+            .reset_time = Some(later);
+        let (status, body) = readyz(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reasons"], unusable);
+        assert_eq!(body["cookies"]["available"], 0);
+
+        let state = ready_state();
+        {
+            let mut config = state.0.config.write();
+            let (removed, _) = config.remove_cookie(&COOKIE.into()).unwrap();
+            let wasted = UselessCookie::new(removed.cookie, UselessReason::Banned);
+            config.wasted_cookie.push(wasted);
+        }
+        let (status, body) = readyz(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reasons"], unusable);
+    }
+}
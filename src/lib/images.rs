@@ -0,0 +1,292 @@
+use futures::StreamExt;
+use image::{
+    DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits,
+    codecs::jpeg::JpegEncoder, imageops::FilterType,
+};
+use rquest::{Client, ClientBuilder, Url, header::LOCATION, redirect::Policy};
+use std::{
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::config::ImageSettings;
+
+/// JPEG qualities tried in turn when recompressing to fit `max_bytes`
+const QUALITIES: [u8; 4] = [85, 70, 55, 40];
+
+/// Redirects followed when fetching an image
+const MAX_REDIRECTS: usize = 5;
+
+/// Media type of an image, going by its magic bytes
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// File name claude.ai is given for an image of `media_type`
+pub fn file_name(media_type: &str) -> &'static str {
+    match media_type {
+        "image/png" => "image.png",
+        "image/jpeg" => "image.jpg",
+        "image/gif" => "image.gif",
+        "image/webp" => "image.webp",
+        _ => "file",
+    }
+}
+
+/// Fetch the image at `url` within the `fetch_timeout` and `fetch_max_bytes` limits
+///
+/// Every hop, redirects included, must resolve to public addresses only unless
+/// its host is in `fetch_allow_hosts`, and must not be in `fetch_deny_hosts`.
+/// Why a fetch failed is logged, the client is only told that it did.
+pub async fn fetch(url: &str, settings: &ImageSettings) -> Result<Vec<u8>, String> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("unsupported url {}", url));
+    }
+    let timeout = Duration::from_secs(settings.fetch_timeout);
+    let reason = match tokio::time::timeout(timeout, fetch_checked(url, settings)).await {
+        Ok(Ok(bytes)) => return Ok(bytes),
+        Ok(Err(FetchError::TooLarge)) => {
+            return Err(format!(
+                "{} is larger than {} bytes",
+                url, settings.fetch_max_bytes
+            ));
+        }
+        Ok(Err(FetchError::Failed(reason))) => reason,
+        Err(_) => format!("timed out after {}s", timeout.as_secs()),
+    };
+    warn!("Failed to fetch image {}: {}", url, reason);
+    Err(format!("failed to fetch {}", url))
+}
+
+enum FetchError {
+    TooLarge,
+    Failed(String),
+}
+
+impl<E: std::fmt::Display> From<E> for FetchError {
+    fn from(e: E) -> Self {
+        FetchError::Failed(e.to_string())
+    }
+}
+
+async fn fetch_checked(url: &str, settings: &ImageSettings) -> Result<Vec<u8>, FetchError> {
+    let mut url = Url::parse(url)?;
+    let mut redirects = 0;
+    let res = loop {
+        let res = pinned_client(&url, settings)
+            .await?
+            .get(url.clone())
+            .send()
+            .await?;
+        if !res.status().is_redirection() {
+            break res;
+        }
+        if redirects == MAX_REDIRECTS {
+            return Err(format!("more than {} redirects", MAX_REDIRECTS).into());
+        }
+        redirects += 1;
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| format!("{} without a location", res.status()))?;
+        url = url.join(location)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("redirected to {}", url).into());
+        }
+    };
+    if !res.status().is_success() {
+        return Err(format!("{} returned {}", url, res.status()).into());
+    }
+    let max = settings.fetch_max_bytes;
+    if res.content_length().is_some_and(|l| l > max) {
+        return Err(FetchError::TooLarge);
+    }
+    let mut stream = res.bytes_stream();
+    let mut bytes = vec![];
+    while let Some(chunk) = stream.next().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() as u64 > max {
+            return Err(FetchError::TooLarge);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Client that connects to `url` only at the addresses checked here
+///
+/// The host is resolved once and the client is pinned to the result, so a
+/// second lookup can't hand it another address. Redirects are left to the
+/// caller.
+async fn pinned_client(url: &Url, settings: &ImageSettings) -> Result<Client, FetchError> {
+    let host = url
+        .host_str()
+        .ok_or("url has no host")?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let listed = |hosts: &[String]| hosts.iter().any(|h| h.eq_ignore_ascii_case(host));
+    if listed(&settings.fetch_deny_hosts) {
+        return Err(format!("{} is in fetch_deny_hosts", host).into());
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = tokio::net::lookup_host((host, port))
+        .await?
+        .collect::<Vec<SocketAddr>>();
+    if addrs.is_empty() {
+        return Err(format!("{} has no address", host).into());
+    }
+    if !listed(&settings.fetch_allow_hosts)
+        && let Some(addr) = addrs.iter().find(|a| !is_public(a.ip()))
+    {
+        return Err(format!("{} resolves to {}", host, addr.ip()).into());
+    }
+    let mut builder = ClientBuilder::new().redirect(Policy::none());
+    if host.parse::<IpAddr>().is_err() {
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+    Ok(builder.build()?)
+}
+
+/// Whether `ip` is reachable on the internet, rather than loopback,
+/// link-local, private, unique-local or otherwise reserved
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Check an image against its declared `media_type` and fit it to the limits
+///
+/// An empty `media_type` is taken from the magic bytes. Images with a side
+/// above `max_dimension` are downscaled and re-encoded, as PNG when they are
+/// transparent and JPEG otherwise. Images still above `max_bytes` are
+/// recompressed as JPEG at falling quality. Returns the bytes to upload and
+/// their media type.
+pub fn prepare(
+    bytes: Vec<u8>,
+    media_type: &str,
+    settings: &ImageSettings,
+) -> Result<(Vec<u8>, &'static str), String> {
+    let Some(sniffed) = sniff(&bytes) else {
+        return Err("data is not a PNG, JPEG, GIF or WebP image".to_string());
+    };
+    if !media_type.is_empty() && media_type != sniffed {
+        return Err(format!(
+            "media_type is {} but the data is {}",
+            media_type, sniffed
+        ));
+    }
+    let format = ImageFormat::from_mime_type(sniffed).ok_or("unsupported image format")?;
+    let too_big = |len: usize| settings.max_bytes > 0 && len as u64 > settings.max_bytes;
+    let max_dimension = settings.max_dimension;
+    if max_dimension == 0 && !too_big(bytes.len()) {
+        return Ok((bytes, sniffed));
+    }
+    let mut image = decode(&bytes, format, settings)?;
+    let mut out = (bytes, sniffed);
+    if max_dimension > 0 && image.width().max(image.height()) > max_dimension {
+        image = image.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+        out = if image.color().has_alpha() {
+            (encode_png(&image)?, "image/png")
+        } else {
+            (encode_jpeg(&image, QUALITIES[0])?, "image/jpeg")
+        };
+    }
+    for quality in QUALITIES {
+        if !too_big(out.0.len()) {
+            return Ok(out);
+        }
+        out = (encode_jpeg(&image, quality)?, "image/jpeg");
+    }
+    if too_big(out.0.len()) {
+        return Err(format!(
+            "still {} bytes after recompression, above max_bytes {}",
+            out.0.len(),
+            settings.max_bytes
+        ));
+    }
+    Ok(out)
+}
+
+/// Decode an image within the `decode_max_dimension` and `decode_max_bytes` limits
+///
+/// The size the image declares is checked before any pixel is decoded.
+fn decode(
+    bytes: &[u8],
+    format: ImageFormat,
+    settings: &ImageSettings,
+) -> Result<DynamicImage, String> {
+    let failed = |e: ImageError| format!("failed to decode image: {}", e);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.no_limits();
+    let mut decoder = reader.into_decoder().map_err(failed)?;
+    let (width, height) = decoder.dimensions();
+    if width.max(height) > settings.decode_max_dimension {
+        return Err(format!(
+            "image is {}x{}, above decode_max_dimension {}",
+            width, height, settings.decode_max_dimension
+        ));
+    }
+    if decoder.total_bytes() > settings.decode_max_bytes {
+        return Err(format!(
+            "image takes {} bytes decoded, above decode_max_bytes {}",
+            decoder.total_bytes(),
+            settings.decode_max_bytes
+        ));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(settings.decode_max_dimension);
+    limits.max_image_height = Some(settings.decode_max_dimension);
+    limits.max_alloc = Some(settings.decode_max_bytes);
+    decoder.set_limits(limits).map_err(failed)?;
+    DynamicImage::from_decoder(decoder).map_err(failed)
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut buf = Cursor::new(vec![]);
+    image
+        .write_to(&mut buf, ImageFormat::Png)
+        .map_err(|e| format!("failed to encode image: {}", e))?;
+    Ok(buf.into_inner())
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+    let mut buf = vec![];
+    JpegEncoder::new_with_quality(&mut buf, quality)
+        .encode_image(&image.to_rgb8())
+        .map_err(|e| format!("failed to encode image: {}", e))?;
+    Ok(buf)
+}
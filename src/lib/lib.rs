@@ -1,15 +1,37 @@
 use clap::Parser;
 use const_format::formatc;
 
+pub mod admin;
+pub mod api;
+pub mod auth;
 pub mod bootstrap;
 pub mod client;
+pub mod clients;
 pub mod config;
 pub mod error;
+pub mod fallback;
+pub mod health;
+pub mod images;
+pub mod message_client;
 pub mod messages;
+pub mod metrics;
+pub mod migrate;
+pub mod models;
+pub mod persist;
+pub mod reload;
 pub mod router;
+pub mod routing;
+pub mod server;
+pub mod sse;
 pub mod state;
+pub mod stats;
 pub mod text;
+pub mod thinking;
+pub mod tokenizer;
+pub mod tools;
 pub mod types;
+pub mod upstream;
+pub mod usage;
 pub mod utils;
 
 pub const TITLE: &str = formatc!(
@@ -21,4 +43,7 @@ pub const TITLE: &str = formatc!(
 #[derive(Parser, Debug)]
 pub struct Args {
     pub cookie_file: Option<String>,
+    /// Convert a clewd `config.js` into `config.toml`
+    #[arg(long)]
+    pub import_clewd: Option<String>,
 }
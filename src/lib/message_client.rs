@@ -0,0 +1,284 @@
+use async_trait::async_trait;
+use axum::{http::HeaderMap, response::Response};
+use futures::{Stream, StreamExt, TryStreamExt, future::ready, stream};
+use serde_json::{Value, json};
+use tracing::debug;
+
+use crate::{
+    error::ClewdrError,
+    messages::ClientRequestBody,
+    state::AppState,
+    types::message::{
+        ContentBlock, ContentBlockDelta, CountMessageTokensParams, CountMessageTokensResponse,
+        CreateMessageParams, CreateMessageResponse, MessageClient, MessageError, Role, StreamEvent,
+        Usage,
+    },
+};
+
+impl From<ClewdrError> for MessageError {
+    fn from(error: ClewdrError) -> Self {
+        MessageError::RequestFailed(error.to_string())
+    }
+}
+
+/// `MessageClient` over the claude.ai web session of an `AppState`
+///
+/// Uses the cookie pool, rotation and prompt handling of the proxy itself,
+/// call `AppState::bootstrap` before the first request.
+#[derive(Clone)]
+pub struct WebMessageClient {
+    state: AppState,
+}
+
+impl WebMessageClient {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    async fn send(&self, params: &CreateMessageParams) -> Result<Response, MessageError> {
+        let mut body = serde_json::to_value(params).map_err(ClewdrError::from)?;
+        // claude.ai always streams, the test message shortcut only answers `stream: false`
+        body["stream"] = true.into();
+        if body["stop_sequences"].is_null() {
+            body["stop_sequences"] = json!([]);
+        }
+        let body: ClientRequestBody = serde_json::from_value(body).map_err(ClewdrError::from)?;
+        Ok(self.state.try_message(body, None, None).await?)
+    }
+}
+
+/// `MessageClient` over the official API, with the `api_keys` of an `AppState`
+#[derive(Clone)]
+pub struct ApiMessageClient {
+    state: AppState,
+}
+
+impl ApiMessageClient {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    async fn send(&self, path: &str, body: Value) -> Result<Response, MessageError> {
+        let res = self
+            .state
+            .try_api_request(path, body, &HeaderMap::new())
+            .await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let text = body_text(res).await?;
+            return Err(MessageError::ApiError(format!("{}: {}", status, text)));
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl MessageClient for WebMessageClient {
+    async fn create_message<'a>(
+        &'a self,
+        params: Option<&'a CreateMessageParams>,
+    ) -> Result<CreateMessageResponse, MessageError> {
+        let params = params.ok_or_else(|| MessageError::RequestFailed("No params".into()))?;
+        collect_message(sse_events(self.send(params).await?)).await
+    }
+
+    async fn count_tokens<'a>(
+        &'a self,
+        params: Option<&'a CountMessageTokensParams>,
+    ) -> Result<CountMessageTokensResponse, MessageError> {
+        let params = params.ok_or_else(|| MessageError::RequestFailed("No params".into()))?;
+        // claude.ai has no token counting endpoint, estimate locally
+        Ok(CountMessageTokensResponse {
+            input_tokens: self
+                .state
+                .estimate_tokens(serde_json::to_value(params).map_err(ClewdrError::from)?)?,
+        })
+    }
+
+    async fn create_message_streaming<'a>(
+        &'a self,
+        body: &'a CreateMessageParams,
+    ) -> Result<
+        impl futures_util::Stream<Item = Result<StreamEvent, MessageError>> + 'a,
+        MessageError,
+    > {
+        Ok(sse_events(self.send(body).await?))
+    }
+}
+
+#[async_trait]
+impl MessageClient for ApiMessageClient {
+    async fn create_message<'a>(
+        &'a self,
+        params: Option<&'a CreateMessageParams>,
+    ) -> Result<CreateMessageResponse, MessageError> {
+        let params = params.ok_or_else(|| MessageError::RequestFailed("No params".into()))?;
+        let mut body = serde_json::to_value(params).map_err(ClewdrError::from)?;
+        body["stream"] = false.into();
+        let res = self.send("messages", body).await?;
+        let text = body_text(res).await?;
+        Ok(serde_json::from_str(&text).map_err(ClewdrError::from)?)
+    }
+
+    async fn count_tokens<'a>(
+        &'a self,
+        params: Option<&'a CountMessageTokensParams>,
+    ) -> Result<CountMessageTokensResponse, MessageError> {
+        let params = params.ok_or_else(|| MessageError::RequestFailed("No params".into()))?;
+        let body = serde_json::to_value(params).map_err(ClewdrError::from)?;
+        let res = self.send("messages/count_tokens", body).await?;
+        let text = body_text(res).await?;
+        Ok(serde_json::from_str(&text).map_err(ClewdrError::from)?)
+    }
+
+    async fn create_message_streaming<'a>(
+        &'a self,
+        body: &'a CreateMessageParams,
+    ) -> Result<
+        impl futures_util::Stream<Item = Result<StreamEvent, MessageError>> + 'a,
+        MessageError,
+    > {
+        let mut body = serde_json::to_value(body).map_err(ClewdrError::from)?;
+        body["stream"] = true.into();
+        Ok(sse_events(self.send("messages", body).await?))
+    }
+}
+
+async fn body_text(res: Response) -> Result<String, MessageError> {
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .map_err(|e| MessageError::RequestFailed(e.to_string()))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Parse an event stream response into `StreamEvent`s
+///
+/// Events this crate does not know, like claude.ai's `message_limit`, are skipped.
+fn sse_events(res: Response) -> impl Stream<Item = Result<StreamEvent, MessageError>> + Send {
+    res.into_body()
+        .into_data_stream()
+        .map_err(|e| MessageError::RequestFailed(e.to_string()))
+        .scan(String::new(), |buffer, chunk| {
+            let events = match chunk {
+                Ok(chunk) => {
+                    buffer.push_str(&String::from_utf8_lossy(&chunk).replace("\r\n", "\n"));
+                    let mut events = vec![];
+                    while let Some(end) = buffer.find("\n\n") {
+                        let raw = buffer[..end].to_string();
+                        buffer.drain(..end + 2);
+                        events.extend(parse_event(&raw).map(Ok));
+                    }
+                    events
+                }
+                Err(e) => vec![Err(e)],
+            };
+            ready(Some(stream::iter(events)))
+        })
+        .flatten()
+}
+
+fn parse_event(raw: &str) -> Option<StreamEvent> {
+    let data = raw
+        .lines()
+        .filter_map(|l| l.strip_prefix("data:"))
+        .map(|l| l.trim_start())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if data.is_empty() {
+        return None;
+    }
+    serde_json::from_str(&data)
+        .inspect_err(|e| debug!("Skipping event: {}: {}", e, data))
+        .ok()
+}
+
+/// Assemble a whole message from its stream events
+///
+/// Fails when the stream has no `message_start` event.
+async fn collect_message(
+    events: impl Stream<Item = Result<StreamEvent, MessageError>>,
+) -> Result<CreateMessageResponse, MessageError> {
+    let mut message = CreateMessageResponse {
+        content: vec![],
+        id: String::new(),
+        model: String::new(),
+        role: Role::Assistant,
+        stop_reason: None,
+        stop_sequence: None,
+        type_: "message".to_string(),
+        usage: Usage::default(),
+    };
+    // partial tool inputs by block index
+    let mut inputs = std::collections::BTreeMap::<usize, String>::new();
+    let mut started = false;
+    let mut events = std::pin::pin!(events);
+    while let Some(event) = events.next().await {
+        match event? {
+            StreamEvent::MessageStart { message: start } => {
+                started = true;
+                message.id = start.id;
+                message.model = start.model;
+                message.usage = start.usage;
+            }
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => {
+                if message.content.len() <= index {
+                    message.content.resize(index + 1, ContentBlock::text(""));
+                }
+                message.content[index] = content_block;
+            }
+            StreamEvent::ContentBlockDelta { index, delta } => {
+                if message.content.len() <= index {
+                    message.content.resize(index + 1, ContentBlock::text(""));
+                }
+                match (&mut message.content[index], delta) {
+                    (ContentBlock::Text { text }, ContentBlockDelta::TextDelta { text: delta }) => {
+                        text.push_str(&delta)
+                    }
+                    (
+                        ContentBlock::Thinking { thinking, .. },
+                        ContentBlockDelta::ThinkingDelta { thinking: delta },
+                    ) => thinking.push_str(&delta),
+                    (
+                        ContentBlock::Thinking { signature, .. },
+                        ContentBlockDelta::SignatureDelta { signature: delta },
+                    ) => signature.push_str(&delta),
+                    (_, ContentBlockDelta::InputJsonDelta { partial_json }) => {
+                        inputs.entry(index).or_default().push_str(&partial_json)
+                    }
+                    _ => {}
+                }
+            }
+            StreamEvent::ContentBlockStop { index } => {
+                if let (Some(json), Some(ContentBlock::ToolUse { input, .. })) =
+                    (inputs.remove(&index), message.content.get_mut(index))
+                {
+                    *input = serde_json::from_str(&json).map_err(ClewdrError::from)?;
+                }
+            }
+            StreamEvent::MessageDelta { delta, usage } => {
+                message.stop_reason = delta.stop_reason;
+                message.stop_sequence = delta.stop_sequence;
+                if let Some(usage) = usage {
+                    message.usage.output_tokens = usage.output_tokens;
+                }
+            }
+            StreamEvent::Error { error } => {
+                return Err(MessageError::ApiError(format!(
+                    "{}: {}",
+                    error.type_, error.message
+                )));
+            }
+            StreamEvent::MessageStop | StreamEvent::Ping => {}
+        }
+    }
+    // e.g. a plain JSON reply to an empty prompt
+    if !started {
+        return Err(MessageError::ApiError(
+            "Response is not a message stream".to_string(),
+        ));
+    }
+    Ok(message)
+}
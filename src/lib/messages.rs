@@ -1,23 +1,38 @@
-use std::{fmt::Debug, mem, sync::LazyLock};
+use std::{fmt::Debug, mem, sync::LazyLock, time::Instant};
 
 use axum::{
-    Json,
+    Extension, Json,
     body::Body,
     extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
-use rquest::header::ACCEPT;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use tracing::{debug, warn};
 
 use crate::{
-    client::{AppendHeaders, SUPER_CLIENT, upload_images},
-    config::UselessReason,
+    api::{BACKEND_HEADER, Backend, select_backend},
+    client::Upload,
+    clients::{anthropic_error, client_name},
+    config::{ClientInfo, UselessReason},
     error::{ClewdrError, check_res_err},
+    fallback::ATTEMPTS_HEADER,
+    metrics::timed_stream,
+    models::metric_label,
+    routing::CookieRoute,
+    sse::rewrite_events,
     state::AppState,
+    stats::RequestRecord,
     text::merge_messages,
-    types::message::{ContentBlock, ImageSource, Message, Role},
+    thinking::ThinkingFilter,
+    tokenizer::count_tokens,
+    tools::{render_tools, tool_stream},
+    types::message::{
+        ContentBlock, CountMessageTokensResponse, Message, Metadata, Role, Tool, ToolChoice,
+    },
+    usage::{StreamLimit, meter_response, meter_stream},
     utils::{TIME_ZONE, print_out_json},
 };
 
@@ -40,10 +55,14 @@ struct Attachment {
 
 impl Attachment {
     fn new(content: String) -> Self {
+        Self::file("paste.txt".to_string(), content)
+    }
+
+    fn file(file_name: String, content: String) -> Self {
         Attachment {
             file_size: content.bytes().len() as u64,
             extracted_content: content,
-            file_name: "paste.txt".to_string(),
+            file_name,
             file_type: "txt".to_string(),
         }
     }
@@ -57,14 +76,32 @@ pub struct RequestBody {
     rendering_mode: String,
     prompt: String,
     timezone: String,
+    /// Only sent with `pass_params`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
     #[serde(skip)]
-    images: Vec<ImageSource>,
+    uploads: Vec<Upload>,
+    /// Tools the model was told about, to parse its calls
+    #[serde(skip)]
+    tools: Vec<Tool>,
+}
+
+impl RequestBody {
+    /// Tokens in the attachments and the prompt
+    fn input_tokens(&self) -> u32 {
+        self.attachments
+            .iter()
+            .map(|a| count_tokens(&a.extracted_content))
+            .sum::<u32>()
+            + count_tokens(&self.prompt)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ClientRequestBody {
     max_tokens: Option<u64>,
     messages: Vec<Message>,
+    #[serde(default)]
     stop_sequences: Vec<String>,
     model: String,
     #[serde(default)]
@@ -72,47 +109,289 @@ pub struct ClientRequestBody {
     thinking: Option<Thinking>,
     #[serde(default)]
     system: Value,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<u32>,
+    metadata: Option<Metadata>,
+    tools: Option<Vec<Tool>>,
+    tool_choice: Option<ToolChoice>,
+}
+
+impl ClientRequestBody {
+    /// Check sampling parameters against the ranges the Messages API accepts
+    pub fn validate(&self) -> Result<(), ClewdrError> {
+        let ranges = [("temperature", self.temperature), ("top_p", self.top_p)];
+        for (name, value) in ranges {
+            if let Some(value) = value.filter(|v| !(0.0..=1.0).contains(v)) {
+                return Err(ClewdrError::InvalidRequest(format!(
+                    "{}: {} is not between 0 and 1",
+                    name, value
+                )));
+            }
+        }
+        if let Some(ToolChoice::Tool { name }) = &self.tool_choice {
+            let tools = self.tools.as_deref().unwrap_or_default();
+            if !tools.iter().any(|t| &t.name == name) {
+                return Err(ClewdrError::InvalidRequest(format!(
+                    "tool_choice: no tool named {}",
+                    name
+                )));
+            }
+        }
+        if let Some(thinking) = self.thinking.as_ref().filter(|t| t.enabled()) {
+            if thinking.budget_tokens < 1024 {
+                return Err(ClewdrError::InvalidRequest(
+                    "thinking.budget_tokens: must be at least 1024".to_string(),
+                ));
+            }
+            if self.max_tokens.is_some_and(|m| thinking.budget_tokens >= m) {
+                return Err(ClewdrError::InvalidRequest(
+                    "thinking.budget_tokens: must be less than max_tokens".to_string(),
+                ));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(ClewdrError::InvalidRequest(
+                "max_tokens: must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Parameters set on the request that claude.ai has no equivalent for
+    fn ignored_params(&self) -> Vec<&'static str> {
+        [
+            ("top_p", self.top_p.is_some()),
+            ("top_k", self.top_k.is_some()),
+            ("metadata", self.metadata.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| name)
+        .collect()
+    }
 }
 
 fn transform(value: ClientRequestBody, user_real_roles: bool) -> Option<RequestBody> {
+    let tools = value.tools.unwrap_or_default();
+    let tool_prompt = render_tools(&tools, value.tool_choice.as_ref());
     let merged = merge_messages(value.messages, user_real_roles)?;
-    let first = merged.head;
+    let first = match &tool_prompt {
+        Some(prompt) => format!("{}\n\n{}", prompt, merged.head),
+        None => merged.head,
+    };
     let last = merged.tail;
-    let images = merged.images;
-    let attachment = Attachment::new(first);
+    let tools = if tool_prompt.is_some() { tools } else { vec![] };
+    let documents = merged
+        .documents
+        .into_iter()
+        .map(|d| Attachment::file(d.file_name, d.content));
+    let attachments = [Attachment::new(first)]
+        .into_iter()
+        .chain(documents)
+        .collect();
     Some(RequestBody {
-        attachments: vec![attachment],
+        attachments,
         files: vec![],
         model: value.model,
         rendering_mode: "messages".to_string(),
         prompt: last,
         timezone: TIME_ZONE.to_string(),
-        images,
+        temperature: None,
+        uploads: merged.uploads,
+        tools,
     })
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct Thinking {
+    #[serde(default)]
     budget_tokens: u64,
     r#type: String,
 }
 
+impl Thinking {
+    fn enabled(&self) -> bool {
+        self.r#type == "enabled"
+    }
+}
+
 pub async fn api_messages(
     State(state): State<AppState>,
-    Json(p): Json<ClientRequestBody>,
+    client: Option<Extension<ClientInfo>>,
+    headers: HeaderMap,
+    Json(mut body): Json<Value>,
 ) -> Response {
-    match state.try_message(p).await {
-        Ok(b) => b.into_response(),
-        Err(e) => {
+    let start = Instant::now();
+    let time = chrono::Utc::now().timestamp();
+    let model = state.resolve_request_model(&mut body);
+    let stream = body["stream"].as_bool().unwrap_or_default();
+    let client = client.map(|Extension(c)| c);
+    if let Some(Err(res)) = client.as_ref().map(|c| state.admit_client(c, &model)) {
+        return res;
+    }
+    let client_name = client_name(client.as_ref()).to_string();
+    let request = body.clone();
+    let (attempts, res) = state
+        .dispatch_message(body, &headers, client.as_ref())
+        .await;
+    let backend = attempts.last().copied().unwrap_or(Backend::Web);
+    let res = match res {
+        Ok(res) => {
+            // web responses already carry estimated usage
+            let input_tokens = match backend {
+                Backend::Web => 0,
+                Backend::Api | Backend::Rproxy => {
+                    state.estimate_tokens(request).unwrap_or_default()
+                }
+            };
+            let state = state.clone();
+            let client_name = client_name.clone();
+            Ok(meter_response(res, input_tokens, move |usage| {
+                state.record_client_usage(&client_name, &usage)
+            })
+            .await)
+        }
+        Err(e) => Err(e),
+    };
+    let (cookie_index, model_label) = {
+        let config = state.0.config.read();
+        (config.index(), metric_label(&config, &model))
+    };
+    let cookie_label = match backend {
+        Backend::Web => cookie_index.to_string(),
+        Backend::Api | Backend::Rproxy => String::new(),
+    };
+    // responses passed through from upstream are counted by status class
+    let status = match &res {
+        Ok(r) if r.status().is_client_error() => "4xx",
+        Ok(r) if r.status().is_server_error() => "5xx",
+        Ok(_) => "ok",
+        Err(e) => e.kind(),
+    };
+    state
+        .0
+        .metrics
+        .requests
+        .with_label_values(&[
+            "/v1/messages",
+            &model_label,
+            status,
+            backend.as_str(),
+            &cookie_label,
+            &client_name,
+        ])
+        .inc();
+    let record = RequestRecord {
+        time,
+        model,
+        stream,
+        backend,
+        client: client_name,
+        cookie_index,
+        duration_ms: start.elapsed().as_millis() as u64,
+        error: res.as_ref().err().map(|e| e.to_string()),
+    };
+    state
+        .0
+        .request_log
+        .push(record, res.as_ref().err().map(|e| e.kind()));
+    let mut response = res.unwrap_or_else(error_response);
+    let attempts = attempts.iter().map(|b| b.as_str()).collect::<Vec<_>>();
+    let headers = response.headers_mut();
+    headers.insert(BACKEND_HEADER, HeaderValue::from_static(backend.as_str()));
+    if let Ok(value) = HeaderValue::from_str(&attempts.join(",")) {
+        headers.insert(ATTEMPTS_HEADER, value);
+    }
+    response
+}
+
+fn error_response(e: ClewdrError) -> Response {
+    match e {
+        ClewdrError::JsonError(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        ClewdrError::InvalidRequest(message) => {
+            warn!("Invalid request: {}", message);
+            anthropic_error(StatusCode::BAD_REQUEST, "invalid_request_error", &message)
+        }
+        ClewdrError::InvalidModel(model) => {
+            warn!("Invalid model: {}", model);
+            let message = format!("Invalid model: {}", model);
+            anthropic_error(StatusCode::BAD_REQUEST, "invalid_request_error", &message)
+        }
+        ClewdrError::NoCookieAvailable(message) => {
+            warn!("No cookie available: {}", message);
+            anthropic_error(StatusCode::SERVICE_UNAVAILABLE, "api_error", &message)
+        }
+        ClewdrError::UploadFailed(message) => {
+            warn!("Upload failed: {}", message);
+            let message = format!("Upload failed: {}", message);
+            anthropic_error(StatusCode::BAD_GATEWAY, "api_error", &message)
+        }
+        ClewdrError::UpstreamStatus(status, text) => {
+            warn!("Upstream returned {}", status);
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+            (status, text).into_response()
+        }
+        e => {
             warn!("Error: {:?}", e);
             e.to_string().into_response()
         }
     }
 }
 
+/// Count the input tokens of a request
+///
+/// Models served by the official API are counted upstream, everything else is
+/// estimated locally from the prompt clewdr would send to claude.ai.
+pub async fn api_count_tokens(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientInfo>>,
+    headers: HeaderMap,
+    Json(mut body): Json<Value>,
+) -> Response {
+    let model = state.resolve_request_model(&mut body);
+    if let Some(Err(res)) = client.map(|Extension(c)| state.check_client_model(&c, &model)) {
+        return res;
+    }
+    let backend = select_backend(&headers, &model, &state.0.config.read());
+    if backend == Backend::Api {
+        match state
+            .try_api_request("messages/count_tokens", body.clone(), &headers)
+            .await
+        {
+            Ok(res) => return res,
+            Err(e) => warn!("Counting tokens upstream failed, estimating instead: {}", e),
+        }
+    }
+    match state.estimate_tokens(body) {
+        Ok(input_tokens) => Json(CountMessageTokensResponse { input_tokens }).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
 impl AppState {
-    async fn try_message(&self, p: ClientRequestBody) -> Result<Response, ClewdrError> {
+    /// Tokens in the prompt `try_message` would send for a Messages API `body`
+    ///
+    /// The prompt is built the same way, tool definitions included.
+    pub fn estimate_tokens(&self, body: Value) -> Result<u32, ClewdrError> {
+        let body = serde_json::from_value::<ClientRequestBody>(body)?;
+        let user_real_roles = self.0.config.read().user_real_roles;
+        Ok(transform(body, user_real_roles).map_or(0, |b| b.input_tokens()))
+    }
+
+    /// Send a request through the claude.ai web session
+    ///
+    /// `client` is who the request is made for, `None` when not known. With a
+    /// `route` the request is served by a cookie of that route only.
+    pub async fn try_message(
+        &self,
+        p: ClientRequestBody,
+        client: Option<&ClientInfo>,
+        route: Option<&CookieRoute>,
+    ) -> Result<Response, ClewdrError> {
         let s = self.0.clone();
+        let start = Instant::now();
+        let model = p.model.clone();
         print_out_json(&p, "0.req.json");
 
         // Check if the request is a test message
@@ -129,38 +408,59 @@ impl AppState {
             .into_response());
         }
 
+        p.validate()?;
+        let cap = s.config.read().max_tokens_cap;
+        let max_tokens = p
+            .max_tokens
+            .map(|t| t.min(u32::MAX as u64) as u32)
+            .into_iter()
+            .chain((cap > 0).then_some(cap))
+            .min();
+        debug!("Message from {} for {}", client_name(client), model);
         // delete the previous conversation if it exists
         self.delete_chat().await?;
         debug!("Chat deleted");
+        let session = self.select_cookie(route, &p.model).await?;
 
         // Create a new conversation
-        *s.conv_uuid.write() = Some(uuid::Uuid::new_v4().to_string());
         let endpoint = s.config.read().endpoint("");
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations",
-            endpoint,
-            s.uuid_org.read()
-        );
+        let uuid_org = s.uuid_org.read().clone();
+        let conv_uuid = uuid::Uuid::new_v4().to_string();
+        *s.conv_uuid.write() = Some(conv_uuid.clone());
         let mut body = json!({
-            "uuid": s.conv_uuid.read().as_ref().unwrap(),
+            "uuid": conv_uuid,
             "name":""
         });
-        if p.thinking.is_some() {
+        let thinking_budget = p
+            .thinking
+            .as_ref()
+            .filter(|t| t.enabled())
+            .map(|t| t.budget_tokens.min(u32::MAX as u64) as u32);
+        if thinking_budget.is_some() {
             body["paprika_mode"] = "extended".into();
             body["model"] = p.model.clone().into();
         }
-        let api_res = SUPER_CLIENT
-            .post(endpoint)
-            .json(&body)
-            .append_headers("", &self.header_cookie()?)
-            .send()
+        let create_start = Instant::now();
+        let api_res = s
+            .upstream
+            .create_conversation(&endpoint, &self.header_cookie()?, &uuid_org, &body)
             .await?;
+        s.metrics
+            .observe_upstream("chat_conversations", create_start);
         debug!("New conversation created");
         self.update_cookie_from_res(&api_res);
-        check_res_err(api_res).await?;
+        check_res_err(api_res, &s.metrics).await?;
 
         // prepare the request
-        let user_real_roles = s.config.read().user_real_roles;
+        let (user_real_roles, pass_params) = {
+            let config = s.config.read();
+            (config.user_real_roles, config.settings.pass_params)
+        };
+        let temperature = p.temperature.filter(|_| pass_params);
+        let ignored = p.ignored_params();
+        if pass_params && !ignored.is_empty() {
+            warn!("claude.ai does not accept {}, ignored", ignored.join(", "));
+        }
         let Some(mut body) = transform(p, user_real_roles) else {
             return Ok(json!({
                 "content": [
@@ -173,40 +473,73 @@ impl AppState {
             .to_string()
             .into_response());
         };
-        // check images
-        let images = mem::take(&mut body.images);
-
-        // upload images
-        let uuid_org = s.uuid_org.read().clone();
-        let files = upload_images(images, self.header_cookie()?, uuid_org).await;
-        body.files = files;
+        let input_tokens = body.input_tokens();
+        body.temperature = temperature;
+        let tools = mem::take(&mut body.tools);
+        // upload images and PDFs, a file that fails fails the request
+        let uploads = mem::take(&mut body.uploads);
+        body.files = self
+            .upload_files(uploads)
+            .await
+            .inspect_err(|_| self.end_chat(&conv_uuid))?;
 
         // file processed
         print_out_json(&body, "4.req.json");
-        let endpoint = s.config.read().endpoint("");
-        let endpoint = format!(
-            "{}/api/organizations/{}/chat_conversations/{}/completion",
-            endpoint,
-            s.uuid_org.read(),
-            s.conv_uuid.read().as_ref().cloned().unwrap_or_default()
-        );
-
-        let api_res = SUPER_CLIENT
-            .post(endpoint)
-            .json(&body)
-            .append_headers("", self.header_cookie()?)
-            .header_append(ACCEPT, "text/event-stream")
-            .send()
+        let completion_start = Instant::now();
+        let api_res = s
+            .upstream
+            .completion(
+                &endpoint,
+                &self.header_cookie()?,
+                &uuid_org,
+                &conv_uuid,
+                &serde_json::to_value(&body)?,
+            )
             .await?;
+        s.metrics.observe_upstream("completion", completion_start);
         self.update_cookie_from_res(&api_res);
-        let api_res = check_res_err(api_res).await.inspect_err(|e| {
+        let api_res = check_res_err(api_res, &s.metrics).await.inspect_err(|e| {
             if let ClewdrError::TooManyRequest(_, i) = e {
+                let index = s.config.read().index().to_string();
+                s.metrics
+                    .rate_limit_reset
+                    .with_label_values(&[index.as_str()])
+                    .set(*i);
                 self.cookie_rotate(UselessReason::Temporary(*i));
             }
         })?;
 
-        // stream the response
-        let input_stream = api_res.bytes_stream();
+        self.record_request();
+        // stream the response, with usage claude.ai does not report
+        let cookie = s.config.read().cookie.clone();
+        let self_clone = self.clone();
+        // claude.ai has no max_tokens, stop reading and drop the conversation
+        let limit = max_tokens.map(|max| {
+            let self_clone = self.clone();
+            let conv_uuid = conv_uuid.clone();
+            StreamLimit::new(max, move || self_clone.end_chat(&conv_uuid))
+        });
+        let label = metric_label(&s.config.read(), &model);
+        let stream = timed_stream(api_res.bytes_stream(), &s.metrics, &label, start);
+        let stream = match thinking_budget {
+            Some(budget) => {
+                let mode = s.config.read().thinking_mode;
+                rewrite_events(stream, ThinkingFilter::new(mode, budget)).boxed()
+            }
+            None => stream.boxed(),
+        };
+        // the model writes tool calls as XML, the calls end its turn
+        let stream = if tools.is_empty() {
+            stream.boxed()
+        } else {
+            let self_clone = self.clone();
+            tool_stream(stream, tools, move || self_clone.end_chat(&conv_uuid)).boxed()
+        };
+        let input_stream = meter_stream(stream, input_tokens, limit, move |usage| {
+            // the cookie may be switched once the response is done
+            drop(session);
+            self_clone.record_cookie_usage(cookie, &usage)
+        });
         Ok(Body::from_stream(input_stream).into_response())
     }
 }
@@ -0,0 +1,197 @@
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+use futures::{Stream, StreamExt};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::time::Instant;
+use tracing::error;
+
+use crate::state::AppState;
+
+/// Buckets for upstream calls, from fast API calls to long generations
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Metrics of one [`AppState`], exposed at `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Requests by route, model, status, backend and cookie index
+    pub requests: IntCounterVec,
+    /// Upstream response time by endpoint
+    pub upstream_latency: HistogramVec,
+    pub time_to_first_token: HistogramVec,
+    pub stream_duration: HistogramVec,
+    /// Cookie rotations by `UselessReason`
+    pub cookie_rotations: IntCounterVec,
+    /// Upstream error responses by status code
+    pub upstream_errors: IntCounterVec,
+    /// Reset timestamp of the last 429 per cookie index
+    pub rate_limit_reset: IntGaugeVec,
+    /// Image uploads by result
+    pub image_uploads: IntCounterVec,
+    /// Cookies in the pool by state, updated on scrape
+    pub cookies: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new_custom(Some("clewdr".to_string()), None)
+            .expect("Failed to create registry");
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests handled"),
+            &[
+                "route",
+                "model",
+                "status",
+                "backend",
+                "cookie_index",
+                "client",
+            ],
+        )
+        .unwrap();
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_latency_seconds",
+                "Time until the upstream responded",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["endpoint"],
+        )
+        .unwrap();
+        let time_to_first_token = HistogramVec::new(
+            HistogramOpts::new(
+                "time_to_first_token_seconds",
+                "Time from request to the first streamed chunk",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["model"],
+        )
+        .unwrap();
+        let stream_duration = HistogramVec::new(
+            HistogramOpts::new("stream_duration_seconds", "Duration of streamed responses")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["model"],
+        )
+        .unwrap();
+        let cookie_rotations = IntCounterVec::new(
+            Opts::new("cookie_rotations_total", "Cookie rotations"),
+            &["reason"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new("upstream_errors_total", "Error responses from claude.ai"),
+            &["status"],
+        )
+        .unwrap();
+        let rate_limit_reset = IntGaugeVec::new(
+            Opts::new(
+                "rate_limit_reset_timestamp",
+                "Unix time when the last 429 of a cookie resets",
+            ),
+            &["cookie_index"],
+        )
+        .unwrap();
+        let image_uploads = IntCounterVec::new(
+            Opts::new("image_uploads_total", "Image uploads"),
+            &["result"],
+        )
+        .unwrap();
+        let cookies =
+            IntGaugeVec::new(Opts::new("cookies", "Cookies in the pool"), &["state"]).unwrap();
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(upstream_latency.clone()),
+            Box::new(time_to_first_token.clone()),
+            Box::new(stream_duration.clone()),
+            Box::new(cookie_rotations.clone()),
+            Box::new(upstream_errors.clone()),
+            Box::new(rate_limit_reset.clone()),
+            Box::new(image_uploads.clone()),
+            Box::new(cookies.clone()),
+        ] {
+            registry.register(collector).unwrap();
+        }
+        Metrics {
+            registry,
+            requests,
+            upstream_latency,
+            time_to_first_token,
+            stream_duration,
+            cookie_rotations,
+            upstream_errors,
+            rate_limit_reset,
+            image_uploads,
+            cookies,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn observe_upstream(&self, endpoint: &str, start: Instant) {
+        self.upstream_latency
+            .with_label_values(&[endpoint])
+            .observe(start.elapsed().as_secs_f64());
+    }
+}
+
+/// Record time to first chunk and total duration of a streamed response
+pub fn timed_stream<S: Stream>(
+    stream: S,
+    metrics: &Metrics,
+    model: &str,
+    start: Instant,
+) -> impl Stream<Item = S::Item> + use<S> {
+    let mut timer = StreamTimer {
+        time_to_first_token: Some(metrics.time_to_first_token.with_label_values(&[model])),
+        stream_duration: metrics.stream_duration.with_label_values(&[model]),
+        start,
+    };
+    stream.inspect(move |_| timer.chunk())
+}
+
+struct StreamTimer {
+    /// Taken on the first chunk
+    time_to_first_token: Option<Histogram>,
+    stream_duration: Histogram,
+    start: Instant,
+}
+
+impl StreamTimer {
+    fn chunk(&mut self) {
+        if let Some(histogram) = self.time_to_first_token.take() {
+            histogram.observe(self.start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+impl Drop for StreamTimer {
+    fn drop(&mut self) {
+        self.stream_duration
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Prometheus text exposition of all metrics
+pub async fn api_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    {
+        let config = state.0.config.read();
+        let now = chrono::Utc::now().timestamp();
+        let temporary = config
+            .cookie_array()
+            .iter()
+            .filter(|c| c.reset_time.is_some_and(|t| t > now))
+            .count();
+        let cookies = &state.0.metrics.cookies;
+        let set = |name: &str, value: usize| cookies.with_label_values(&[name]).set(value as i64);
+        set("healthy", config.cookie_array_len() - temporary);
+        set("temporary", temporary);
+        set("wasted", config.wasted_cookie.len());
+    }
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&state.0.metrics.registry.gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+    }
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], buffer)
+}
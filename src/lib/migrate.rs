@@ -0,0 +1,397 @@
+use colored::Colorize;
+use serde_json::Value;
+use toml::{Table, Value as TomlValue};
+use tracing::{info, warn};
+
+use crate::{config::Config, error::ClewdrError};
+
+/// Version of the config layout written by this build
+pub const CURRENT_CONFIG_VERSION: u32 = 1;
+
+/// Keys inherited from clewd that clewdr does not implement
+const CLEWD_ONLY_KEYS: [&str; 2] = ["personality_format", "scenario_format"];
+const CLEWD_ONLY_SETTINGS: [&str; 9] = [
+    "retry_regenerate",
+    "all_samples",
+    "no_samples",
+    "strip_assistant",
+    "strip_human",
+    "clear_flags",
+    "full_colon",
+    "xml_plot",
+    "superfetch",
+];
+
+/// A migration upgrades a config table from version `n` to `n + 1`
+type Migration = fn(&mut Table);
+
+/// Migrations indexed by the version they upgrade from
+const MIGRATIONS: [Migration; CURRENT_CONFIG_VERSION as usize] = [migrate_v0];
+
+/// Bring a raw config table up to [`CURRENT_CONFIG_VERSION`]
+pub fn migrate(table: &mut Table) {
+    let version = table
+        .get("config_version")
+        .and_then(|v| v.as_integer())
+        .unwrap_or(0)
+        .max(0) as u32;
+    if version > CURRENT_CONFIG_VERSION {
+        warn!(
+            "Config version {} is newer than supported version {}",
+            version, CURRENT_CONFIG_VERSION
+        );
+        return;
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(table);
+        info!("Migrated config from version {} to {}", from, from + 1);
+    }
+    table.insert(
+        "config_version".to_string(),
+        TomlValue::Integer(CURRENT_CONFIG_VERSION as i64),
+    );
+}
+
+/// v0: configs copied from clewd, without a version
+fn migrate_v0(table: &mut Table) {
+    let mut dropped = vec![];
+    for key in CLEWD_ONLY_KEYS {
+        if table.remove(key).is_some() {
+            dropped.push(key.to_string());
+        }
+    }
+    if let Some(settings) = table.get_mut("settings").and_then(|s| s.as_table_mut()) {
+        for key in CLEWD_ONLY_SETTINGS {
+            if settings.remove(key).is_some() {
+                dropped.push(format!("settings.{}", key));
+            }
+        }
+    }
+    if !dropped.is_empty() {
+        warn!(
+            "Options not supported by clewdr are removed: {}",
+            dropped.join(", ").yellow()
+        );
+    }
+    table
+        .entry("user_real_roles")
+        .or_insert(TomlValue::Boolean(false));
+}
+
+/// Warn about keys that clewdr does not know and will drop on the next save
+pub fn warn_unknown_keys(table: &Table) {
+    let Ok(known) = Table::try_from(Config::default()) else {
+        return;
+    };
+    let mut unknown = table
+        .keys()
+        .filter(|k| !known.contains_key(*k))
+        .cloned()
+        .collect::<Vec<_>>();
+    if let (Some(settings), Some(known_settings)) = (
+        table.get("settings").and_then(|s| s.as_table()),
+        known.get("settings").and_then(|s| s.as_table()),
+    ) {
+        unknown.extend(
+            settings
+                .keys()
+                .filter(|k| !known_settings.contains_key(*k))
+                .map(|k| format!("settings.{}", k)),
+        );
+    }
+    if !unknown.is_empty() {
+        warn!(
+            "Unknown config keys will be dropped on next save: {}",
+            unknown.join(", ").yellow()
+        );
+    }
+}
+
+/// Top level keys of clewd's `Config` and their clewdr names
+const CLEWD_KEYS: [(&str, &str); 17] = [
+    ("Cookie", "cookie"),
+    ("unknownModels", "unknown_models"),
+    ("CookieCounter", "cookie_counter"),
+    ("CookieIndex", "cookie_index"),
+    ("ProxyPassword", "proxy_password"),
+    ("Ip", "ip"),
+    ("Port", "port"),
+    ("localtunnel", "local_tunnel"),
+    ("BufferSize", "buffer_size"),
+    ("SystemInterval", "system_interval"),
+    ("rProxy", "rproxy"),
+    ("api_rProxy", "api_rproxy"),
+    ("placeholder_token", "placeholder_token"),
+    ("placeholder_byte", "placeholder_byte"),
+    ("PromptExperimentFirst", "prompt_experiment_first"),
+    ("PromptExperimentNext", "prompt_experiment_next"),
+    ("UserRealRoles", "user_real_roles"),
+];
+
+/// clewd's `Settings` keys and their clewdr names
+const CLEWD_SETTINGS: [(&str, &str); 10] = [
+    ("RenewAlways", "renew_always"),
+    ("PromptExperiments", "prompt_experiments"),
+    ("SystemExperiments", "system_experiments"),
+    ("PreventImperson", "prevent_imperson"),
+    ("PassParams", "pass_params"),
+    ("PreserveChats", "preserve_chats"),
+    ("LogMessages", "log_messages"),
+    ("padtxt", "padtxt"),
+    ("SkipRestricted", "skip_restricted"),
+    ("Artifacts", "artifacts"),
+];
+
+/// Convert clewd's `config.js` (or its `Config` object as JSON) into a clewdr config
+pub fn import_clewd(source: &str) -> Result<Config, ClewdrError> {
+    let json = extract_object(source).ok_or(ClewdrError::PathNotFound(
+        "No config object found in clewd config".to_string(),
+    ))?;
+    let clewd: Value = serde_json::from_str(json)?;
+    let clewd = clewd.as_object().ok_or(ClewdrError::UnexpectedNone)?;
+    let mut table = Table::try_from(Config::default())?;
+    // drop the placeholder cookies of the default config
+    table.insert("cookie_array".to_string(), TomlValue::Array(vec![]));
+    let mut skipped = vec![];
+    let mut nulls = vec![];
+
+    for (key, value) in clewd {
+        // TOML has no null, keep the default
+        if value.is_null() {
+            nulls.push(key.to_string());
+            continue;
+        }
+        if let Some((_, name)) = CLEWD_KEYS.iter().find(|(k, _)| k == key) {
+            let value = match *name {
+                // clewd counts from 1, 0 meaning unset
+                "cookie_index" => Value::from(value.as_i64().unwrap_or(0).max(1) - 1),
+                _ => value.clone(),
+            };
+            table.insert(name.to_string(), TomlValue::try_from(value)?);
+            continue;
+        }
+        match key.as_str() {
+            "CookieArray" => {
+                let array = value
+                    .as_array()
+                    .map(|a| a.iter().filter_map(import_cookie).collect())
+                    .unwrap_or_default();
+                table.insert("cookie_array".to_string(), TomlValue::Array(array));
+            }
+            "WastedCookie" => {
+                let array = value
+                    .as_array()
+                    .map(|a| a.iter().filter_map(import_wasted).collect())
+                    .unwrap_or_default();
+                table.insert("wasted_cookie".to_string(), TomlValue::Array(array));
+            }
+            "Settings" => {
+                let Some(settings) = table.get_mut("settings").and_then(|s| s.as_table_mut())
+                else {
+                    continue;
+                };
+                for (key, value) in value.as_object().into_iter().flatten() {
+                    match CLEWD_SETTINGS.iter().find(|(k, _)| k == key) {
+                        Some(_) if value.is_null() => nulls.push(format!("Settings.{}", key)),
+                        Some((_, name)) => {
+                            settings.insert(name.to_string(), TomlValue::try_from(value)?);
+                        }
+                        None => skipped.push(format!("Settings.{}", key)),
+                    }
+                }
+            }
+            _ => skipped.push(key.to_string()),
+        }
+    }
+    if !skipped.is_empty() {
+        warn!(
+            "clewd options not supported by clewdr are skipped: {}",
+            skipped.join(", ").yellow()
+        );
+    }
+    if !nulls.is_empty() {
+        warn!(
+            "clewd options set to null are skipped, defaults are used: {}",
+            nulls.join(", ").yellow()
+        );
+    }
+    let toml_string = toml::ser::to_string(&table)?;
+    Config::parse(&toml_string)
+}
+
+/// `model@cookie` or plain cookie string from clewd's `CookieArray`
+fn import_cookie(value: &Value) -> Option<TomlValue> {
+    let value = value.as_str()?.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let mut cookie = Table::new();
+    match value.split_once('@') {
+        Some((model, c)) if !model.is_empty() => {
+            cookie.insert("cookie".to_string(), c.into());
+            cookie.insert("model".to_string(), model.into());
+        }
+        _ => {
+            cookie.insert("cookie".to_string(), value.into());
+        }
+    }
+    Some(TomlValue::Table(cookie))
+}
+
+/// `reason@cookie` or plain cookie string from clewd's `WastedCookie`
+fn import_wasted(value: &Value) -> Option<TomlValue> {
+    let value = value.as_str()?.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let (reason, cookie) = value.split_once('@').unwrap_or(("", value));
+    let reason = ["Disabled", "Unverified", "Overlap", "Banned", "Invalid"]
+        .into_iter()
+        .find(|r| r.eq_ignore_ascii_case(reason))
+        .unwrap_or("Null");
+    let mut wasted = Table::new();
+    wasted.insert("cookie".to_string(), cookie.into());
+    wasted.insert("reason".to_string(), reason.into());
+    Some(TomlValue::Table(wasted))
+}
+
+/// Find the outermost `{ ... }` object in a JS or JSON source, skipping strings
+fn extract_object(source: &str) -> Option<&str> {
+    let source = source
+        .find("module.exports")
+        .map_or(source, |i| &source[i..]);
+    let start = source.find('{')?;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in source[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&source[start..=start + i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UselessReason;
+
+    const CLEWD_CONFIG: &str = include_str!("../../tests/fixtures/clewd_config.js");
+
+    #[test]
+    fn import_maps_clewd_keys() {
+        let config = import_clewd(CLEWD_CONFIG).unwrap();
+        let default = Config::default();
+        assert_eq!(config.config_version, CURRENT_CONFIG_VERSION);
+        assert_eq!(config.cookie_counter, 5);
+        assert_eq!(config.proxy_password, "hunter2");
+        assert_eq!(config.address(), "127.0.0.1:8444");
+        assert_eq!(config.unknown_models, ["claude-2.1"]);
+        assert!(!config.settings.renew_always);
+        assert!(config.settings.pass_params);
+        assert_eq!(config.settings.padtxt, "1000,1000,15000");
+        // null options keep their defaults
+        assert_eq!(config.api_rproxy, default.api_rproxy);
+        assert_eq!(config.settings.log_messages, default.settings.log_messages);
+    }
+
+    #[test]
+    fn import_converts_cookie_index_from_one_based() {
+        let config = import_clewd(CLEWD_CONFIG).unwrap();
+        assert_eq!(config.index(), 1);
+        let unset = CLEWD_CONFIG.replace("\"CookieIndex\": 2", "\"CookieIndex\": 0");
+        assert_eq!(import_clewd(&unset).unwrap().index(), 0);
+    }
+
+    #[test]
+    fn import_splits_cookie_models_and_wasted_reasons() {
+        let config = import_clewd(CLEWD_CONFIG).unwrap();
+        let cookies = config.cookie_array();
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[0].model, None);
+        assert_eq!(cookies[1].model.as_deref(), Some("claude-3-opus"));
+        assert!(cookies.iter().all(|c| c.cookie.validate()));
+        let reasons = config
+            .wasted_cookie
+            .iter()
+            .map(|c| c.reason.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, [UselessReason::Banned, UselessReason::Null]);
+    }
+
+    #[test]
+    fn import_skips_clewd_only_keys() {
+        let config = import_clewd(CLEWD_CONFIG).unwrap();
+        let toml = toml::to_string(&config).unwrap();
+        for key in [
+            "personality",
+            "scenario",
+            "superfetch",
+            "xml_plot",
+            "xmlPlot",
+        ] {
+            assert!(!toml.contains(key), "{} was imported", key);
+        }
+    }
+
+    #[test]
+    fn import_rejects_source_without_object() {
+        assert!(import_clewd("// no config here").is_err());
+    }
+
+    #[test]
+    fn migrate_v0_drops_clewd_only_keys() {
+        let mut table: Table = toml::from_str(
+            r#"
+            personality_format = "{{char}}"
+            port = 8484
+            [settings]
+            retry_regenerate = true
+            padtxt = "1000"
+            "#,
+        )
+        .unwrap();
+        migrate(&mut table);
+        assert!(!table.contains_key("personality_format"));
+        assert_eq!(table["port"].as_integer(), Some(8484));
+        let settings = table["settings"].as_table().unwrap();
+        assert!(!settings.contains_key("retry_regenerate"));
+        assert_eq!(settings["padtxt"].as_str(), Some("1000"));
+        assert_eq!(table["user_real_roles"].as_bool(), Some(false));
+        assert_eq!(
+            table["config_version"].as_integer(),
+            Some(CURRENT_CONFIG_VERSION as i64)
+        );
+    }
+
+    #[test]
+    fn migrate_keeps_current_configs() {
+        let mut table: Table = toml::from_str(
+            r#"
+            config_version = 1
+            user_real_roles = true
+            "#,
+        )
+        .unwrap();
+        migrate(&mut table);
+        assert_eq!(table["user_real_roles"].as_bool(), Some(true));
+    }
+}
@@ -0,0 +1,207 @@
+use axum::http::HeaderMap;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+
+use crate::{config::Config, state::AppState};
+
+/// Models clewdr knows the metadata of: id, display name, release date, context window
+const CATALOG: [(&str, &str, &str, u32); 6] = [
+    (
+        "claude-3-7-sonnet-20250219",
+        "Claude 3.7 Sonnet",
+        "2025-02-19",
+        200_000,
+    ),
+    (
+        "claude-3-5-sonnet-20241022",
+        "Claude 3.5 Sonnet (New)",
+        "2024-10-22",
+        200_000,
+    ),
+    (
+        "claude-3-5-haiku-20241022",
+        "Claude 3.5 Haiku",
+        "2024-10-22",
+        200_000,
+    ),
+    (
+        "claude-3-5-sonnet-20240620",
+        "Claude 3.5 Sonnet (Old)",
+        "2024-06-20",
+        200_000,
+    ),
+    (
+        "claude-3-opus-20240229",
+        "Claude 3 Opus",
+        "2024-02-29",
+        200_000,
+    ),
+    (
+        "claude-3-haiku-20240307",
+        "Claude 3 Haiku",
+        "2024-03-07",
+        200_000,
+    ),
+];
+
+/// Context window assumed for models missing from the catalog
+const DEFAULT_CONTEXT_WINDOW: u32 = 200_000;
+
+/// Aliases always accepted, `model_aliases` in the config can override them
+const BUILTIN_ALIASES: [(&str, &str); 7] = [
+    ("claude-3-7-sonnet-latest", "claude-3-7-sonnet-20250219"),
+    ("claude-3-5-sonnet-latest", "claude-3-5-sonnet-20241022"),
+    ("claude-3-5-haiku-latest", "claude-3-5-haiku-20241022"),
+    ("claude-3-opus-latest", "claude-3-opus-20240229"),
+    ("sonnet", "claude-3-7-sonnet-20250219"),
+    ("haiku", "claude-3-5-haiku-20241022"),
+    ("opus", "claude-3-opus-20240229"),
+];
+
+/// Whether `model` is in the catalog
+pub fn is_known(model: &str) -> bool {
+    CATALOG.iter().any(|(id, ..)| *id == model)
+}
+
+/// The model `name` stands for, `name` itself when it is no alias
+pub fn resolve_model(config: &Config, name: &str) -> String {
+    let name = config.model_aliases.get(name).map_or(name, |m| m.as_str());
+    BUILTIN_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, model)| model)
+        .to_string()
+}
+
+/// `model` when it is a catalog id, one of `api_models` or a configured
+/// alias, `"other"` otherwise, so metric labels stay bounded
+pub fn metric_label(config: &Config, model: &str) -> String {
+    let known = is_known(model)
+        || config.api_models.iter().any(|m| m == model)
+        || config
+            .model_aliases
+            .iter()
+            .any(|(alias, target)| alias == model || target == model);
+    if known { model } else { "other" }.to_string()
+}
+
+/// Cheapest way to use a model
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Plan {
+    /// Default model of a free cookie
+    Free,
+    /// Needs a pro or team cookie
+    Pro,
+    /// Served by the official API, see `api_models`
+    Api,
+}
+
+/// An entry of `/v1/models`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+    pub display_name: String,
+    /// `YYYY-MM-DD`, unknown for models missing from the catalog
+    pub created: Option<&'static str>,
+    pub owned_by: &'static str,
+    pub context_window: u32,
+    pub plan: Plan,
+}
+
+impl ModelInfo {
+    pub fn new(id: &str, plan: Plan) -> Self {
+        let entry = CATALOG.iter().find(|(m, ..)| *m == id);
+        Self {
+            id: id.to_string(),
+            display_name: entry.map_or(id, |e| e.1).to_string(),
+            created: entry.map(|e| e.2),
+            owned_by: "anthropic",
+            context_window: entry.map_or(DEFAULT_CONTEXT_WINDOW, |e| e.3),
+            plan,
+        }
+    }
+
+    fn created_timestamp(&self) -> i64 {
+        self.created
+            .and_then(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map_or(0, |d| d.and_utc().timestamp())
+    }
+
+    /// Entry of an Anthropic `/v1/models` list
+    pub fn to_anthropic(&self) -> Value {
+        json!({
+            "type": "model",
+            "id": self.id,
+            "display_name": self.display_name,
+            "created_at": self.created.map(|d| format!("{}T00:00:00Z", d)),
+            "owned_by": self.owned_by,
+            "context_window": self.context_window,
+            "plan": self.plan,
+        })
+    }
+
+    /// Entry of an OpenAI `/v1/models` list
+    pub fn to_openai(&self) -> Value {
+        json!({
+            "id": self.id,
+            "object": "model",
+            "created": self.created_timestamp(),
+            "owned_by": self.owned_by,
+            "context_window": self.context_window,
+            "plan": self.plan,
+        })
+    }
+}
+
+/// Whether a `/v1/models` request comes from an Anthropic SDK rather than an OpenAI one
+pub fn wants_anthropic_list(headers: &HeaderMap) -> bool {
+    headers.contains_key("anthropic-version") || headers.contains_key("x-api-key")
+}
+
+impl AppState {
+    /// Replace an alias in the `model` of a request body, returning the model
+    pub fn resolve_request_model(&self, body: &mut Value) -> String {
+        let Some(model) = body.get_mut("model") else {
+            return String::new();
+        };
+        let resolved = resolve_model(&self.0.config.read(), model.as_str().unwrap_or_default());
+        if !resolved.is_empty() {
+            *model = resolved.clone().into();
+        }
+        resolved
+    }
+
+    /// Models the pool can serve, sorted by id
+    ///
+    /// Free cookies report their default model at bootstrap, a pro or team
+    /// cookie makes the whole catalog available. Until any cookie has
+    /// reported its plan the catalog is listed as needing pro.
+    pub fn available_models(&self) -> Vec<ModelInfo> {
+        let config = self.0.config.read();
+        let mut models = BTreeMap::new();
+        let cookies = config.cookie_array();
+        let has_paid = cookies.iter().any(|c| c.is_pro());
+        if has_paid || cookies.iter().all(|c| c.model.is_none()) {
+            for (id, ..) in CATALOG {
+                models.insert(id.to_string(), Plan::Pro);
+            }
+        }
+        for model in cookies
+            .iter()
+            .filter(|c| !c.is_pro())
+            .filter_map(|c| c.model.as_ref())
+        {
+            models.insert(model.clone(), Plan::Free);
+        }
+        for model in &config.api_models {
+            models.entry(model.clone()).or_insert(Plan::Api);
+        }
+        models
+            .into_iter()
+            .map(|(id, plan)| ModelInfo::new(&id, plan))
+            .collect()
+    }
+}
@@ -0,0 +1,309 @@
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fs::{File, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{runtime::Handle, time::sleep};
+use tracing::{debug, error, warn};
+
+use crate::error::ClewdrError;
+
+/// Time to wait after the first save request before writing to disk,
+/// so that bursts of saves (e.g. during cookie rotation) are coalesced
+pub const SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Advisory locks held for the whole lifetime of the process, by lock file
+static INSTANCE_LOCKS: LazyLock<Mutex<BTreeMap<PathBuf, File>>> = LazyLock::new(Default::default);
+
+/// A snapshot of a file waiting to be written
+struct PendingWrite {
+    content: String,
+    backups: usize,
+}
+
+/// Debounced, atomic writes of the files of one config
+///
+/// Clones share the same queue. Each `AppState` gets its own through its
+/// config, so states in one process never wait on each other's writes.
+#[derive(Clone, Default)]
+pub struct Persister(Arc<PersisterInner>);
+
+#[derive(Default)]
+struct PersisterInner {
+    /// Latest snapshot per file
+    pending: Mutex<BTreeMap<PathBuf, PendingWrite>>,
+    /// Serializes writes so a flush never interleaves with another one
+    writing: Mutex<()>,
+    /// Whether a debounce task is waiting to flush
+    scheduled: AtomicBool,
+    /// Content of each file as last written or read, to tell our own writes from user edits
+    seen: Mutex<BTreeMap<PathBuf, String>>,
+}
+
+impl std::fmt::Debug for Persister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Persister").finish_non_exhaustive()
+    }
+}
+
+/// Held by a debounce task, clears `scheduled` however the task ends
+///
+/// If the task is dropped before it flushes, e.g. with the runtime that
+/// spawned it, the pending snapshots are written on the spot.
+struct ScheduledFlush(Option<Persister>);
+
+impl ScheduledFlush {
+    /// Take over the flush, later writes schedule a new one
+    fn release(mut self) -> Persister {
+        let persister = self.0.take().expect("released once");
+        persister.0.scheduled.store(false, Ordering::Release);
+        persister
+    }
+}
+
+impl Drop for ScheduledFlush {
+    fn drop(&mut self) {
+        if let Some(persister) = self.0.take() {
+            persister.0.scheduled.store(false, Ordering::Release);
+            persister.flush();
+        }
+    }
+}
+
+impl Persister {
+    /// Queue `content` to be written to `path`
+    ///
+    /// Inside a Tokio runtime the write is debounced by [`SAVE_DEBOUNCE`] and
+    /// only the latest snapshot is written. Outside a runtime the write
+    /// happens at once.
+    pub fn schedule_write(&self, path: PathBuf, content: String, backups: usize) {
+        self.0
+            .pending
+            .lock()
+            .insert(path, PendingWrite { content, backups });
+        let Ok(handle) = Handle::try_current() else {
+            self.flush();
+            return;
+        };
+        if self.0.scheduled.swap(true, Ordering::AcqRel) {
+            // a flush is already on its way and will pick up the latest snapshot
+            return;
+        }
+        let guard = ScheduledFlush(Some(self.clone()));
+        handle.spawn(async move {
+            sleep(SAVE_DEBOUNCE).await;
+            let persister = guard.release();
+            if let Err(e) = tokio::task::spawn_blocking(move || persister.flush()).await {
+                error!("Config flush task failed: {}", e);
+            }
+        });
+    }
+
+    /// Write the pending snapshots, if any, to disk right away
+    ///
+    /// A file edited by someone else since we last wrote or read it is not
+    /// overwritten. Its snapshot stays pending until the edit has been read
+    /// with [`Persister::mark_read`] and the file is saved again.
+    pub fn flush(&self) {
+        let _guard = self.0.writing.lock();
+        let pending = std::mem::take(&mut *self.0.pending.lock());
+        for (path, pending) in pending {
+            if self.edited(&path) {
+                warn!(
+                    "{} was edited, not saving over it before it is reloaded",
+                    path.display()
+                );
+                self.0.pending.lock().entry(path).or_insert(pending);
+                continue;
+            }
+            match write_atomic(&path, &pending.content, pending.backups) {
+                Ok(()) => {
+                    debug!("Saved {}", path.display());
+                    self.0.seen.lock().insert(path, pending.content);
+                }
+                Err(e) => error!("Failed to save {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Whether `path` differs from what we last wrote or read
+    fn edited(&self, path: &Path) -> bool {
+        let Some(seen) = self.0.seen.lock().get(path).cloned() else {
+            return false;
+        };
+        std::fs::read_to_string(path).is_ok_and(|content| content != seen)
+    }
+
+    /// Record that `content` was read from `path`, it is ours to save over
+    pub fn mark_read(&self, path: &Path, content: &str) {
+        self.0
+            .seen
+            .lock()
+            .insert(path.to_path_buf(), content.to_string());
+    }
+
+    /// Whether a snapshot of `path` is waiting to be written
+    pub fn is_pending(&self, path: &Path) -> bool {
+        self.0.pending.lock().contains_key(path)
+    }
+
+    /// Whether `content` is exactly what this persister last wrote or read at `path`
+    pub fn is_own_write(&self, path: &Path, content: &str) -> bool {
+        self.0.seen.lock().get(path).is_some_and(|c| c == content)
+    }
+}
+
+/// Write `content` to `path` via a temp file, fsync and rename,
+/// keeping up to `backups` previous versions next to it
+pub fn write_atomic(path: &Path, content: &str, backups: usize) -> std::io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let tmp_path = sibling(path, "tmp");
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.sync_all()?;
+    }
+    if backups > 0 && path.exists() {
+        rotate_backups(path, backups).unwrap_or_else(|e| {
+            warn!("Failed to back up config: {}", e);
+        });
+    }
+    std::fs::rename(&tmp_path, path)?;
+    // persist the rename itself, not supported on every platform
+    if let Ok(dir) = File::open(dir) {
+        dir.sync_all().ok();
+    }
+    Ok(())
+}
+
+/// Shift `config.toml.bak.1..n` up by one and copy the current file to `.bak.1`
+fn rotate_backups(path: &Path, backups: usize) -> std::io::Result<()> {
+    for i in (1..backups).rev() {
+        let from = sibling(path, &format!("bak.{}", i));
+        if from.exists() {
+            std::fs::rename(&from, sibling(path, &format!("bak.{}", i + 1)))?;
+        }
+    }
+    std::fs::copy(path, sibling(path, "bak.1"))?;
+    Ok(())
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Take an advisory lock on `<config>.lock`, failing if another instance holds it
+///
+/// Each config path is locked on its own, locking one this process already
+/// holds succeeds.
+pub fn lock_instance(config_path: &Path) -> Result<(), ClewdrError> {
+    let lock_path = std::path::absolute(sibling(config_path, "lock"))?;
+    let mut locks = INSTANCE_LOCKS.lock();
+    if locks.contains_key(&lock_path) {
+        return Ok(());
+    }
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+    match file.try_lock() {
+        Ok(()) => {
+            locks.insert(lock_path, file);
+            Ok(())
+        }
+        Err(TryLockError::WouldBlock) => {
+            Err(ClewdrError::ConfigLocked(lock_path.display().to_string()))
+        }
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh directory for one test, with the config path in it
+    fn config_in_temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clewdr-persist-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("config.toml")
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn atomic_write_leaves_no_temp_file() {
+        let path = config_in_temp_dir();
+        write_atomic(&path, "first", 0).unwrap();
+        write_atomic(&path, "second", 0).unwrap();
+        assert_eq!(read(&path), "second");
+        assert!(!sibling(&path, "tmp").exists());
+        assert!(!sibling(&path, "bak.1").exists());
+    }
+
+    #[test]
+    fn backups_are_rotated_up_to_the_count() {
+        let path = config_in_temp_dir();
+        for version in ["v1", "v2", "v3", "v4"] {
+            write_atomic(&path, version, 2).unwrap();
+        }
+        assert_eq!(read(&path), "v4");
+        assert_eq!(read(&sibling(&path, "bak.1")), "v3");
+        assert_eq!(read(&sibling(&path, "bak.2")), "v2");
+        assert!(!sibling(&path, "bak.3").exists());
+    }
+
+    #[test]
+    fn external_edits_are_not_overwritten_before_they_are_read() {
+        let path = config_in_temp_dir();
+        let persister = Persister::default();
+        // outside a runtime writes go out at once
+        persister.schedule_write(path.clone(), "ours".to_string(), 0);
+        assert!(persister.is_own_write(&path, "ours"));
+
+        std::fs::write(&path, "edited").unwrap();
+        persister.schedule_write(path.clone(), "newer".to_string(), 0);
+        assert_eq!(read(&path), "edited");
+        assert!(persister.is_pending(&path));
+        assert!(!persister.is_own_write(&path, "edited"));
+
+        persister.mark_read(&path, "edited");
+        persister.flush();
+        assert_eq!(read(&path), "newer");
+        assert!(!persister.is_pending(&path));
+    }
+
+    #[test]
+    fn locks_are_taken_per_config() {
+        let path = config_in_temp_dir();
+        lock_instance(&path).unwrap();
+        // already ours
+        lock_instance(&path).unwrap();
+        let other = config_in_temp_dir();
+        lock_instance(&other).unwrap();
+        assert!(INSTANCE_LOCKS.lock().len() >= 2);
+    }
+
+    #[test]
+    fn a_config_locked_elsewhere_is_refused() {
+        let path = config_in_temp_dir();
+        // another instance holding the lock
+        let held = File::create(sibling(&path, "lock")).unwrap();
+        held.try_lock().unwrap();
+        let err = lock_instance(&path).unwrap_err();
+        assert!(matches!(err, ClewdrError::ConfigLocked(_)), "{:?}", err);
+    }
+}
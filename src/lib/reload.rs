@@ -0,0 +1,118 @@
+use colored::Colorize;
+use std::time::{Duration, SystemTime};
+use tokio::{spawn, time::interval};
+use tracing::{error, info, warn};
+
+use crate::{config::Config, error::ClewdrError, state::AppState};
+
+/// How often the config file is checked for modifications
+pub const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+impl AppState {
+    /// Re-read `config.toml` and merge it into the running config
+    ///
+    /// An invalid file is rejected and the running config is kept as is.
+    /// Returns the list of changes that were applied.
+    pub async fn reload_config(&self) -> Result<Vec<String>, ClewdrError> {
+        let path = self.0.config.read().file_path()?;
+        let file_string = tokio::fs::read_to_string(&path).await?;
+        self.reload_from_str(&file_string).await
+    }
+
+    /// Merge `file_string` once the requests on the current cookie are done
+    async fn reload_from_str(&self, file_string: &str) -> Result<Vec<String>, ClewdrError> {
+        let new_config = Config::parse(file_string).inspect_err(|e| {
+            error!("Invalid config file, keeping running config: {}", e);
+        })?;
+        // the merge may change the pool under the current cookie
+        let session = self.0.session.clone().write_owned().await;
+        let (changes, added, rebootstrap) = {
+            let mut config = self.0.config.write();
+            let before = config
+                .cookie_array()
+                .iter()
+                .map(|c| c.cookie.clone())
+                .collect::<Vec<_>>();
+            let changes = config.merge_reloaded(new_config);
+            let added = config
+                .cookie_array()
+                .iter()
+                .filter(|c| !before.contains(&c.cookie))
+                .count();
+            // the edit is read, saves held back for it can go out now
+            let path = config.file_path()?;
+            config.persister().mark_read(&path, file_string);
+            if !changes.is_empty() || config.persister().is_pending(&path) {
+                config.save()?;
+            }
+            let rebootstrap =
+                (before.is_empty() && added > 0) || changes.iter().any(|c| c == "cookie");
+            (changes, added, rebootstrap)
+        };
+        drop(session);
+        self.extend_init_length(added as u64);
+        if changes.is_empty() {
+            info!("Config reloaded, nothing changed");
+            return Ok(changes);
+        }
+        info!("Config reloaded: {}", changes.join(", ").green());
+        if rebootstrap {
+            self.rebootstrap();
+        }
+        Ok(changes)
+    }
+
+    /// Reload the config on SIGHUP and whenever the file is modified
+    pub fn spawn_config_watcher(&self) {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            let self_clone = self.clone();
+            spawn(async move {
+                let mut hup = match signal(SignalKind::hangup()) {
+                    Ok(hup) => hup,
+                    Err(e) => {
+                        error!("Failed to listen for SIGHUP: {}", e);
+                        return;
+                    }
+                };
+                while hup.recv().await.is_some() {
+                    info!("SIGHUP received, reloading config");
+                    self_clone.reload_config().await.ok();
+                }
+            });
+        }
+        let self_clone = self.clone();
+        spawn(async move {
+            let mut last_modified = self_clone.modified_time().await;
+            let mut ticker = interval(CONFIG_POLL_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let modified = self_clone.modified_time().await;
+                if modified.is_none() || modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                let Ok(path) = self_clone.0.config.read().file_path() else {
+                    continue;
+                };
+                let Ok(file_string) = tokio::fs::read_to_string(&path).await else {
+                    warn!("Failed to read modified config file");
+                    continue;
+                };
+                // skip the writes we made ourselves
+                let persister = self_clone.0.config.read().persister().clone();
+                if persister.is_own_write(&path, &file_string) {
+                    continue;
+                }
+                info!("Config file modified, reloading");
+                self_clone.reload_from_str(&file_string).await.ok();
+            }
+        });
+    }
+
+    async fn modified_time(&self) -> Option<SystemTime> {
+        let path = self.0.config.read().file_path().ok()?;
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+}
@@ -2,14 +2,25 @@ use axum::{
     Json, Router,
     extract::{Request, State},
     http::{HeaderMap, StatusCode},
+    middleware,
     response::Html,
     routing::{get, options, post},
 };
-use const_format::{concatc, formatc};
+use const_format::formatc;
 use serde_json::{Value, json};
+use std::sync::LazyLock;
 use tower_http::trace::TraceLayer;
 
-use crate::{client::NORMAL_CLIENT, messages::api_messages, state::AppState, utils::MODELS};
+use crate::{
+    admin::{admin_router, require_admin},
+    auth::authenticate,
+    client::NORMAL_CLIENT,
+    health::{api_healthz, api_readyz},
+    messages::{api_count_tokens, api_messages},
+    metrics::api_metrics,
+    models::wants_anthropic_list,
+    state::AppState,
+};
 
 pub struct RouterBuilder {
     inner: Router,
@@ -21,10 +32,22 @@ impl RouterBuilder {
             inner: Router::new()
                 .route("/v1/models", get(get_models))
                 .route("/v1/messages", post(api_messages))
+                .route("/v1/messages/count_tokens", post(api_count_tokens))
+                .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+                // metrics are labelled by client name, keep them admin-only
+                .route(
+                    "/metrics",
+                    get(api_metrics)
+                        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin)),
+                )
                 .route("/v1", options(api_options))
-                .route("/", options(api_options))
+                .route("/", get(api_fallback).options(api_options))
+                .nest("/admin", admin_router(state.clone()))
                 .fallback(api_fallback)
                 .layer(TraceLayer::new_for_http())
+                // probes are polled often, keep them out of the trace logs
+                .route("/healthz", get(api_healthz))
+                .route("/readyz", get(api_readyz))
                 .with_state(state),
         }
     }
@@ -36,9 +59,10 @@ impl RouterBuilder {
 
 async fn get_models(
     headers: HeaderMap,
-    State(api_state): State<AppState>,
+    State(state): State<AppState>,
 ) -> Result<Json<Value>, StatusCode> {
-    let api_state = api_state.0;
+    let api_state = state.0.clone();
+    let anthropic = wants_anthropic_list(&headers);
     let authorization = headers
         .get("Authorization")
         .and_then(|h| h.to_str().ok())
@@ -67,74 +91,56 @@ async fn get_models(
     } else {
         vec![]
     };
-    let config = api_state.config.read();
-    let mut data = MODELS
+    let mut data = state
+        .available_models()
         .iter()
-        .cloned()
-        .chain(config.unknown_models.iter().map(|s| s.as_str()))
-        .map(|name| {
-            json!({
-                "id":name,
-            })
+        .map(|m| {
+            if anthropic {
+                m.to_anthropic()
+            } else {
+                m.to_openai()
+            }
         })
         .chain(models)
         .collect::<Vec<_>>();
-    data.sort_unstable_by(|a, b| {
+    data.sort_by(|a, b| {
         let a = a["id"].as_str().unwrap_or("");
         let b = b["id"].as_str().unwrap_or("");
         a.cmp(b)
     });
-    data.dedup();
-    let response = json!({
-        "data": data,
-    });
+    data.dedup_by(|a, b| a["id"] == b["id"]);
+    let response = if anthropic {
+        json!({
+            "data": data,
+            "has_more": false,
+            "first_id": data.first().map(|m| m["id"].clone()),
+            "last_id": data.last().map(|m| m["id"].clone()),
+        })
+    } else {
+        json!({
+            "object": "list",
+            "data": data,
+        })
+    };
     Ok(Json(response))
 }
 
-async fn api_fallback(req: Request) -> Html<&'static str> {
-    let url = req.uri().path();
-    if !["/", "/v1", "/favicon.ico"].contains(&url) {
-        println!("Unknown request url: {}", url);
-    }
+/// Dashboard page, data is loaded from the admin API
+static DASHBOARD: LazyLock<String> = LazyLock::new(|| {
     const VX_BY_AUTHOR: &str = formatc!(
         "v{} by {}",
         env!("CARGO_PKG_VERSION"),
         env!("CARGO_PKG_AUTHORS")
     );
-    Html(concatc!(
-        r#"
-<html><head>
-<meta charset="utf-8">
-<script>
-function copyToClipboard(text) {
-  var textarea = document.createElement("textarea");
-  textarea.textContent = text;
-  textarea.style.position = "fixed";
-  document.body.appendChild(textarea);
-  textarea.select();
-  try {
-    return document.execCommand("copy");
-  } catch (ex) {
-    console.warn("Copy to clipboard failed.", ex);
-    return false;
-  } finally {
-    document.body.removeChild(textarea);
-  }
-}
-function copyLink(event) {
-  event.preventDefault();
-  const url = new URL(window.location.href);
-  const link = url.protocol + '//' + url.host + '/v1';
-  copyToClipboard(link);
-  alert('链接已复制: ' + link);
-}
-</script>
-<style id="VMst0.014418824593286361">rt.katakana-terminator-rt::before { content: attr(data-rt); }</style><script id="simplify-jobs-page-script" src="chrome-extension://pbanhockgagggenencehbnadejlgchfc/js/pageScript.bundle.js"></script></head>
-<body>
-Clewdr "#,
-        VX_BY_AUTHOR,
-        r#"<br><br>完全开源、免费且禁止商用<br><br>点击复制反向代理: <a href="v1" onclick="copyLink(event)">Copy Link</a><br>填入OpenAI API反向代理并选择OpenAI分类中的claude模型（酒馆需打开Show "External" models，仅在api模式有模型选择差异）<br><br>教程与FAQ: <a href="https://rentry.org/teralomaniac_clewd" target="FAQ">Rentry</a> | <a href="https://discord.com/invite/B7Wr25Z7BZ" target="FAQ">Discord</a><br><br><br>❗警惕任何高风险cookie/伪api(25k cookie)购买服务，以及破坏中文AI开源共享环境倒卖免费资源抹去署名的群组（🈲黑名单：酒馆小二、AI新服务、浅睡(鲑鱼)、赛博女友制作人(青麈/overloaded/科普晓百生)🈲）</body></html>"#
-    ))
+    include_str!("dashboard.html").replace("{{VERSION}}", VX_BY_AUTHOR)
+});
+
+async fn api_fallback(req: Request) -> Html<&'static str> {
+    let url = req.uri().path();
+    if !["/", "/v1", "/favicon.ico"].contains(&url) {
+        println!("Unknown request url: {}", url);
+    }
+    Html(DASHBOARD.as_str())
 }
 
 async fn api_options() -> HeaderMap {
@@ -0,0 +1,228 @@
+use axum::http::HeaderMap;
+use std::{collections::HashMap, fmt::Display};
+use tokio::sync::OwnedRwLockReadGuard;
+use tracing::info;
+
+use crate::{
+    clients::client_name,
+    config::{ClientInfo, Config, CookieInfo, RouteRule},
+    error::ClewdrError,
+    state::AppState,
+};
+
+/// Cookies allowed to serve a request
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CookieRoute {
+    pub group: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl CookieRoute {
+    pub fn matches(&self, cookie: &CookieInfo) -> bool {
+        self.group
+            .as_ref()
+            .is_none_or(|g| cookie.group.as_ref() == Some(g))
+            && self.tags.iter().all(|t| cookie.tags.contains(t))
+    }
+}
+
+impl Display for CookieRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.group {
+            Some(group) => write!(f, "group {}", group)?,
+            None => write!(f, "any group")?,
+        }
+        if !self.tags.is_empty() {
+            write!(f, " tagged {}", self.tags.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl RouteRule {
+    fn matches(&self, headers: &HeaderMap, model: &str, client: &str) -> bool {
+        (self.models.is_empty() || self.models.iter().any(|m| model_matches(m, model)))
+            && (self.clients.is_empty() || self.clients.iter().any(|c| c == client))
+            && self.headers.iter().all(|(name, value)| {
+                headers
+                    .get(name.as_str())
+                    .and_then(|h| h.to_str().ok())
+                    .is_some_and(|h| h == value)
+            })
+    }
+}
+
+fn model_matches(pattern: &str, model: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => model.starts_with(prefix),
+        None => pattern == model,
+    }
+}
+
+/// Cookies that may serve a request, `None` when any cookie will do
+///
+/// The `cookie_group` of the client comes first, then the first matching rule
+/// of `routes`.
+pub fn route_for(
+    config: &Config,
+    headers: &HeaderMap,
+    model: &str,
+    client: Option<&ClientInfo>,
+) -> Option<CookieRoute> {
+    if let Some(group) = client.and_then(|c| c.cookie_group.clone()) {
+        return Some(CookieRoute {
+            group: Some(group),
+            tags: vec![],
+        });
+    }
+    let name = client_name(client);
+    config
+        .routes
+        .iter()
+        .find(|r| r.matches(headers, model, name))
+        .map(|r| CookieRoute {
+            group: r.group.clone(),
+            tags: r.tags.clone(),
+        })
+}
+
+/// Whether `cookie` can serve `model`, `None` while its plan is unknown
+///
+/// Pro and team plans serve every model, free cookies only their default one.
+pub fn can_serve(cookie: &CookieInfo, model: &str) -> Option<bool> {
+    match &cookie.model {
+        None => None,
+        Some(_) if cookie.is_pro() => Some(true),
+        Some(m) => Some(m == model),
+    }
+}
+
+/// Cookies of the pool by the models they can serve, built from detected plans
+#[derive(Debug, Default)]
+pub struct ModelIndex {
+    /// Paid plans, any model
+    paid: Vec<usize>,
+    /// Free cookies by their default model
+    by_model: HashMap<String, Vec<usize>>,
+    /// Not bootstrapped yet
+    unknown: Vec<usize>,
+}
+
+impl ModelIndex {
+    pub fn new(cookies: &[CookieInfo]) -> Self {
+        let mut index = Self::default();
+        for (i, cookie) in cookies.iter().enumerate() {
+            match &cookie.model {
+                None => index.unknown.push(i),
+                Some(_) if cookie.is_pro() => index.paid.push(i),
+                Some(model) => index.by_model.entry(model.clone()).or_default().push(i),
+            }
+        }
+        index
+    }
+
+    /// Cookies known to serve `model`, then the ones whose plan is unknown
+    pub fn capable(&self, model: &str) -> Vec<usize> {
+        let mut known = self
+            .paid
+            .iter()
+            .chain(self.by_model.get(model).into_iter().flatten())
+            .copied()
+            .collect::<Vec<_>>();
+        known.sort_unstable();
+        known.extend(&self.unknown);
+        known
+    }
+}
+
+impl AppState {
+    /// Make current a cookie that can serve `model`, within `route` if given
+    ///
+    /// The current cookie is kept when it fits. Otherwise the next fitting
+    /// cookie not waiting for its `reset_time` is bootstrapped, preferring
+    /// cookies whose plan is known. Fails with `InvalidModel` when no cookie
+    /// of the pool could ever serve the request, and with `NoCookieAvailable`
+    /// when those that could, or every cookie of the route, are unavailable.
+    ///
+    /// The returned guard keeps the session on that cookie, hold it until the
+    /// response is done. A switch waits for the requests holding one.
+    pub async fn select_cookie(
+        &self,
+        route: Option<&CookieRoute>,
+        model: &str,
+    ) -> Result<OwnedRwLockReadGuard<()>, ClewdrError> {
+        let fits = |c: &CookieInfo| route.is_none_or(|r| r.matches(c));
+        let current_fits = |config: &Config| {
+            // single `cookie` mode, nothing to choose from
+            config.cookie_array_len() == 0
+                || usize::try_from(config.index())
+                    .ok()
+                    .and_then(|i| config.cookie_array().get(i))
+                    .is_some_and(|c| fits(c) && can_serve(c, model) != Some(false))
+        };
+        let session = self.0.session.clone().read_owned().await;
+        if current_fits(&self.0.config.read()) {
+            return Ok(session);
+        }
+        drop(session);
+        let session = self.0.session.clone().write_owned().await;
+        let target = match route {
+            Some(route) => format!("{} in {}", model, route),
+            None => model.to_string(),
+        };
+        let mut tried = vec![];
+        loop {
+            let index = {
+                let mut config = self.0.config.write();
+                // another request may have switched while this one waited
+                if current_fits(&config) {
+                    return Ok(session.downgrade());
+                }
+                let cookies = config.cookie_array();
+                if let Some(route) = route.filter(|r| !cookies.iter().any(|c| r.matches(c))) {
+                    return Err(ClewdrError::NoCookieAvailable(format!(
+                        "no cookie in {}",
+                        route
+                    )));
+                }
+                let candidates = ModelIndex::new(cookies)
+                    .capable(model)
+                    .into_iter()
+                    .filter(|i| fits(&cookies[*i]))
+                    .collect::<Vec<_>>();
+                if candidates.is_empty() {
+                    return Err(ClewdrError::InvalidModel(format!(
+                        "{}, no cookie can serve it",
+                        target
+                    )));
+                }
+                let now = chrono::Utc::now().timestamp();
+                let len = cookies.len();
+                let start = config.index().max(0) as usize;
+                let index = candidates
+                    .into_iter()
+                    .filter(|i| {
+                        !tried.contains(&cookies[*i].cookie)
+                            && cookies[*i].reset_time.is_none_or(|t| t <= now)
+                    })
+                    .min_by_key(|i| {
+                        let known = can_serve(&cookies[*i], model).is_some();
+                        (!known, (i + len - start - 1) % len)
+                    })
+                    .ok_or_else(|| {
+                        ClewdrError::NoCookieAvailable(format!(
+                            "every cookie for {} is rate limited or unusable",
+                            target
+                        ))
+                    })?;
+                // bootstrap may clean cookies out, indices don't last
+                tried.push(cookies[index].cookie.clone());
+                config.set_index(index);
+                config.save()?;
+                index
+            };
+            info!("Switching to cookie {} for {}", index, target);
+            self.bootstrap_locked(&session).await;
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use colored::Colorize;
+use std::{future::Future, path::PathBuf, pin::Pin, time::Duration};
+use tokio::{net::TcpListener, time::timeout};
+use tracing::{info, warn};
+
+use crate::{config::Config, error::ClewdrError, persist, router::RouterBuilder, state::AppState};
+
+/// Longest wait for background chat deletions once the server has stopped
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Shutdown = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// clewdr as a library, for running it inside another Tokio application
+pub struct ClewdrServer {
+    state: AppState,
+    listener: Option<TcpListener>,
+    shutdown: Option<Shutdown>,
+}
+
+impl ClewdrServer {
+    /// Serve `config`
+    ///
+    /// Without a file set by [`Config::with_file`] the config and usage are
+    /// kept in memory: nothing is written to disk and no file is watched.
+    pub fn new(config: Config) -> Self {
+        Self::from_state(AppState::new(config))
+    }
+
+    /// Serve an existing state, e.g. one built with `AppState::with_upstream`
+    pub fn from_state(state: AppState) -> Self {
+        Self {
+            state,
+            listener: None,
+            shutdown: None,
+        }
+    }
+
+    /// Load the config from `path`, changes are saved back to it
+    pub fn from_path(path: impl Into<PathBuf>) -> Result<Self, ClewdrError> {
+        let path = path.into();
+        persist::lock_instance(&path)?;
+        let content = std::fs::read_to_string(&path)?;
+        let config = Config::parse(&content)?.with_file(&path);
+        config.persister().mark_read(&path, &content);
+        Ok(Self::new(config))
+    }
+
+    /// Accept connections on `listener` instead of binding `ip:port` from the config
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Stop when `shutdown` completes instead of on SIGINT/SIGTERM
+    pub fn with_shutdown(mut self, shutdown: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Box::pin(shutdown));
+        self
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Bootstrap, serve until shutdown, then drain and persist
+    ///
+    /// In-flight requests, streams included, are allowed to finish. Then
+    /// pending chat deletions are awaited and the config is written to its file.
+    pub async fn serve(self) -> Result<(), ClewdrError> {
+        let state = self.state;
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => {
+                let addr = state.0.config.read().address();
+                TcpListener::bind(addr).await?
+            }
+        };
+        println!(
+            "Listening on {}",
+            listener.local_addr()?.to_string().green()
+        );
+        let router = RouterBuilder::new(state.clone()).build();
+        state.bootstrap().await;
+        if state.0.config.read().has_file() {
+            state.spawn_config_watcher();
+        }
+        let shutdown = self.shutdown.unwrap_or_else(|| Box::pin(shutdown_signal()));
+        axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown)
+            .await?;
+
+        info!("Server stopped, cleaning up");
+        if timeout(SHUTDOWN_TIMEOUT, state.drain()).await.is_err() {
+            warn!("Timed out waiting for chat deletions");
+        }
+        let config = state.0.config.read();
+        config.save()?;
+        config.persister().flush();
+        Ok(())
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received");
+}
@@ -0,0 +1,87 @@
+use axum::body::Bytes;
+use futures::{Stream, StreamExt, stream};
+use serde_json::Value;
+
+/// Rewrites a server-sent event stream one event at a time
+pub trait EventRewriter {
+    /// Events replacing `event`, each without its terminating blank line
+    fn rewrite(&mut self, event: &str) -> Vec<String>;
+
+    /// Whether the rest of the upstream stream is to be dropped
+    fn stopped(&self) -> bool {
+        false
+    }
+}
+
+/// JSON `data` of an event, if any
+pub fn event_data(event: &str) -> Option<Value> {
+    let data = event.lines().find_map(|l| l.strip_prefix("data:"))?;
+    serde_json::from_str(data.trim_start()).ok()
+}
+
+/// An event carrying `value`, named after its `type`
+pub fn event(value: &Value) -> String {
+    format!(
+        "event: {}\ndata: {}",
+        value["type"].as_str().unwrap_or_default(),
+        value
+    )
+}
+
+/// Pass `stream` through `rewriter`, ending it once the rewriter stops
+pub fn rewrite_events<S, E, R>(stream: S, rewriter: R) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    R: EventRewriter,
+{
+    let rewriting = Rewriting {
+        buffer: vec![],
+        rewriter,
+    };
+    stream::unfold(
+        (Box::pin(stream), rewriting, false),
+        |(mut stream, mut rewriting, done)| async move {
+            if done {
+                return None;
+            }
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    let out = rewriting.feed(&chunk);
+                    let stopped = rewriting.rewriter.stopped();
+                    Some((Ok(out), (stream, rewriting, stopped)))
+                }
+                Some(Err(e)) => Some((Err(e), (stream, rewriting, false))),
+                None => {
+                    let rest = Bytes::from(std::mem::take(&mut rewriting.buffer));
+                    Some((Ok(rest), (stream, rewriting, true)))
+                }
+            }
+        },
+    )
+}
+
+struct Rewriting<R> {
+    /// Bytes of an incomplete event, `\r` stripped
+    buffer: Vec<u8>,
+    rewriter: R,
+}
+
+impl<R: EventRewriter> Rewriting<R> {
+    /// Rewrite the complete events in `chunk`, keeping the rest for later
+    fn feed(&mut self, chunk: &[u8]) -> Bytes {
+        self.buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+        let mut out = String::new();
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let raw = self.buffer.drain(..end + 2).collect::<Vec<_>>();
+            for event in self.rewriter.rewrite(&String::from_utf8_lossy(&raw[..end])) {
+                out.push_str(&event);
+                out.push_str("\n\n");
+            }
+            if self.rewriter.stopped() {
+                self.buffer.clear();
+                break;
+            }
+        }
+        Bytes::from(out)
+    }
+}
@@ -1,34 +1,58 @@
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use regex::Regex;
 use regex::RegexBuilder;
 use rquest::Response;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::{collections::HashMap, sync::Arc};
+use tokio::sync::OwnedRwLockWriteGuard;
 use tokio::time::sleep;
-use tokio::{spawn, time::Duration};
+use tokio::{spawn, task::JoinHandle, time::Duration};
 use tracing::debug;
 use tracing::error;
 use tracing::warn;
 
-use crate::client::AppendHeaders;
-use crate::client::SUPER_CLIENT;
+use crate::clients::RequestWindows;
+use crate::config::Cookie;
 use crate::config::UselessReason;
 use crate::error::ClewdrError;
+use crate::metrics::Metrics;
+use crate::stats::RequestLog;
+use crate::upstream::{SharedUpstream, Upstream};
+use crate::usage::{USAGE_FILE, UsageTotals};
 use crate::{config::Config, utils::ENDPOINT};
 
 #[derive(Default)]
 pub struct InnerState {
     pub config: RwLock<Config>,
-    init_length: u64,
+    init_length: AtomicU64,
+    /// Rotations since startup, compared with `init_length`
+    shifts: AtomicU64,
     rotating: AtomicBool,
+    bootstrapped: AtomicBool,
     pub is_pro: RwLock<Option<String>>,
     pub uuid_org: RwLock<String>,
-    pub model: RwLock<Option<String>>,
     cookies: RwLock<HashMap<String, String>>,
     pub uuid_org_array: RwLock<Vec<String>>,
     pub conv_uuid: RwLock<Option<String>>,
+    /// Completed requests per cookie since startup
+    pub request_counts: RwLock<HashMap<Cookie, u64>>,
+    pub request_log: RequestLog,
+    /// Token totals per cookie and client key, saved to `usage.toml`
+    pub usage: RwLock<UsageTotals>,
+    /// Recent requests per client, for `ClientInfo::rpm`
+    pub request_windows: RequestWindows,
+    /// claude.ai or a stand-in, see `AppState::with_upstream`
+    pub upstream: SharedUpstream,
+    pub metrics: Metrics,
+    /// Round robin position in `api_keys`
+    pub(crate) api_key_index: AtomicUsize,
+    /// Held shared by web requests for their cookie, exclusively to switch it
+    pub(crate) session: Arc<tokio::sync::RwLock<()>>,
+    /// Chat deletions still running, awaited on shutdown
+    background: Mutex<Vec<JoinHandle<()>>>,
 }
 
 #[derive(Clone)]
@@ -36,15 +60,101 @@ pub struct AppState(pub Arc<InnerState>);
 
 impl AppState {
     pub fn new(config: Config) -> Self {
+        Self::with_upstream(config, SharedUpstream::default().0)
+    }
+
+    /// State that talks to `upstream` instead of claude.ai
+    pub fn with_upstream(config: Config, upstream: Arc<dyn Upstream>) -> Self {
+        let usage = config
+            .file_path()
+            .map(|p| UsageTotals::load(&p.with_file_name(USAGE_FILE)))
+            .unwrap_or_default();
         let m = InnerState {
-            init_length: config.cookie_array_len() as u64,
+            init_length: AtomicU64::new(config.cookie_array_len() as u64),
+            usage: RwLock::new(usage),
             config: RwLock::new(config),
+            upstream: SharedUpstream(upstream),
             ..Default::default()
         };
         let m = Arc::new(m);
         AppState(m)
     }
 
+    /// Account for cookies added to the pool after startup
+    pub fn extend_init_length(&self, added: u64) {
+        self.0.init_length.fetch_add(added, Ordering::Relaxed);
+    }
+
+    pub fn is_rotating(&self) -> bool {
+        self.0.rotating.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_rotating(&self, rotating: bool) {
+        self.0.rotating.store(rotating, Ordering::Relaxed);
+    }
+
+    /// Whether any cookie has completed bootstrap since startup
+    pub fn is_bootstrapped(&self) -> bool {
+        self.0.bootstrapped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_bootstrapped(&self) {
+        self.0.bootstrapped.store(true, Ordering::Relaxed);
+    }
+
+    /// Count a completed request against the cookie in use
+    pub fn record_request(&self) {
+        let cookie = self.0.config.read().cookie.clone();
+        *self.0.request_counts.write().entry(cookie).or_default() += 1;
+    }
+
+    /// Run bootstrap again in the background, e.g. after the pool changed
+    pub fn rebootstrap(&self) {
+        let self_clone = self.clone();
+        spawn(async move {
+            self_clone.bootstrap().await;
+        });
+    }
+
+    /// Spawn a task that shutdown waits for
+    fn spawn_background(&self, fut: impl Future<Output = ()> + Send + 'static) {
+        let mut background = self.0.background.lock();
+        background.retain(|h| !h.is_finished());
+        background.push(spawn(fut));
+    }
+
+    /// Wait for background chat deletions and delete the last conversation
+    pub async fn drain(&self) {
+        let handles = std::mem::take(&mut *self.0.background.lock());
+        for handle in handles {
+            handle.await.ok();
+        }
+        if let Err(err) = self.delete_chat().await {
+            error!("Failed to delete chat: {:?}", err);
+        }
+    }
+
+    /// Switch to the next cookie without marking the current one as useless
+    ///
+    /// Waits for the requests on the current cookie to finish first.
+    pub fn force_rotate(&self) {
+        let self_clone = self.clone();
+        self.spawn_background(async move {
+            let session = self_clone.0.session.clone().write_owned().await;
+            if let Err(err) = self_clone.delete_chat().await {
+                error!("Failed to delete chat: {:?}", err);
+            }
+            {
+                let mut config = self_clone.0.config.write();
+                config.rotate_cookie();
+                config.save().unwrap_or_else(|e| {
+                    error!("Failed to save config: {}", e);
+                });
+            }
+            self_clone.bootstrap_locked(&session).await;
+        });
+    }
+
     pub fn update_cookie_from_res(&self, res: &Response) {
         if let Some(s) = res
             .headers()
@@ -93,15 +203,57 @@ impl AppState {
             .to_string())
     }
 
+    /// Mark the current cookie useless for `reason` and rotate to the next one
+    ///
+    /// The switch waits for the requests on the cookie to finish. If another
+    /// switch came first, only a `Temporary` reset time is kept for the cookie.
     pub fn cookie_rotate(&self, reason: UselessReason) {
+        let cookie = self.0.config.read().current_cookie().cloned();
         let self_clone = self.clone();
         spawn(async move {
-            if let Ok(err) = self_clone.delete_chat().await {
+            let session = self_clone.0.session.clone().write_owned().await;
+            let mut config = self_clone.0.config.write();
+            if config.current_cookie() == cookie.as_ref() {
+                drop(config);
+                self_clone.rotate_locked(reason, &session);
+                return;
+            }
+            debug!("Cookie already switched, not rotating");
+            if let UselessReason::Temporary(i) = reason
+                && let Some(info) = cookie.as_ref().and_then(|c| config.cookie_info_mut(c))
+            {
+                info.reset_time = Some(i);
+                config.save().unwrap_or_else(|e| {
+                    error!("Failed to save config: {}", e);
+                });
+            }
+        });
+    }
+
+    /// Mark the current cookie useless for `reason` and rotate to the next one
+    ///
+    /// The caller holds `session` exclusively, no request is on the cookie.
+    pub(crate) fn rotate_locked(
+        &self,
+        reason: UselessReason,
+        _session: &OwnedRwLockWriteGuard<()>,
+    ) {
+        let label = match reason {
+            UselessReason::Temporary(_) => "Temporary".to_string(),
+            ref r => r.to_string(),
+        };
+        self.0
+            .metrics
+            .cookie_rotations
+            .with_label_values(&[label])
+            .inc();
+        let self_clone = self.clone();
+        self.spawn_background(async move {
+            if let Err(err) = self_clone.delete_chat().await {
                 error!("Failed to delete chat: {:?}", err);
             }
         });
-        static SHIFTS: AtomicU64 = AtomicU64::new(0);
-        if SHIFTS.load(Ordering::Relaxed) == self.0.init_length {
+        if self.0.shifts.load(Ordering::Relaxed) == self.0.init_length.load(Ordering::Relaxed) {
             error!("Cookie used up, not rotating");
             return;
         }
@@ -109,6 +261,7 @@ impl AppState {
         let Some(current_cookie) = config.current_cookie_info() else {
             return;
         };
+        let group = current_cookie.group.clone();
         match reason {
             UselessReason::Temporary(i) => {
                 warn!("Temporary useless cookie, not cleaning");
@@ -122,11 +275,18 @@ impl AppState {
                 config.cookie_cleaner(reason);
             }
         }
-        // rotate the cookie
-        config.rotate_cookie();
+        // rotate the cookie, staying in its group
+        let rotated = config.rotate_cookie_in(group.as_deref());
         config.save().unwrap_or_else(|e| {
             error!("Failed to save config: {}", e);
         });
+        if !rotated {
+            error!(
+                "Cookies of group {} used up, not rotating",
+                group.as_deref().unwrap_or("(none)")
+            );
+            return;
+        }
         // set timeout callback
         let dur = if config.rproxy.is_empty() || config.rproxy == ENDPOINT {
             warn!("Waiting 15 seconds to change cookie");
@@ -136,16 +296,29 @@ impl AppState {
         };
         let dur = Duration::from_secs(dur as u64);
         let self_clone = self.clone();
-        SHIFTS.fetch_add(1, Ordering::Relaxed);
+        self.0.shifts.fetch_add(1, Ordering::Relaxed);
         spawn(async move {
-            self_clone.0.rotating.store(true, Ordering::Relaxed);
+            self_clone.set_rotating(true);
             sleep(dur).await;
             warn!("Cookie rotating complete");
-            self_clone.0.rotating.store(false, Ordering::Relaxed);
+            self_clone.set_rotating(false);
             self_clone.bootstrap().await;
         });
     }
 
+    /// Delete conversation `uuid` in the background, unless a newer one replaced it
+    pub fn end_chat(&self, uuid: &str) {
+        if self.0.conv_uuid.read().as_deref() != Some(uuid) {
+            return;
+        }
+        let self_clone = self.clone();
+        self.spawn_background(async move {
+            if let Err(err) = self_clone.delete_chat().await {
+                error!("Failed to delete chat: {:?}", err);
+            }
+        });
+    }
+
     pub async fn delete_chat(&self) -> Result<(), ClewdrError> {
         let uuid = self.0.conv_uuid.write().take();
         let config = self.0.config.read().clone();
@@ -158,12 +331,15 @@ impl AppState {
             return Ok(());
         }
         debug!("Deleting chat: {}", uuid);
-        let endpoint = config.endpoint("api/organizations");
-        let endpoint = format!("{}/{}/chat_conversations/{}", endpoint, uuid_org, uuid);
-        let res = SUPER_CLIENT
-            .delete(endpoint.clone())
-            .append_headers("", self.header_cookie()?)
-            .send()
+        let res = self
+            .0
+            .upstream
+            .delete_conversation(
+                &config.endpoint(""),
+                &self.header_cookie()?,
+                &uuid_org,
+                &uuid,
+            )
             .await?;
         self.update_cookie_from_res(&res);
         Ok(())
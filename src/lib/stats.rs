@@ -0,0 +1,64 @@
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::api::Backend;
+
+/// Number of requests kept for the dashboard
+pub const RECENT_REQUESTS: usize = 50;
+
+/// Summary of a handled `/v1/messages` request
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestRecord {
+    /// Unix timestamp when the request arrived
+    pub time: i64,
+    pub model: String,
+    pub stream: bool,
+    pub backend: Backend,
+    /// Client name, see `clients::client_name`
+    pub client: String,
+    /// Cookie used by the web backend
+    pub cookie_index: i32,
+    /// Milliseconds until the response started
+    pub duration_ms: u64,
+    /// Error message, `None` on success
+    pub error: Option<String>,
+}
+
+/// Recent requests and error counts since startup
+#[derive(Default)]
+pub struct RequestLog {
+    recent: RwLock<VecDeque<RequestRecord>>,
+    errors: RwLock<BTreeMap<&'static str, u64>>,
+    total: AtomicU64,
+}
+
+impl RequestLog {
+    pub fn push(&self, record: RequestRecord, error_kind: Option<&'static str>) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if let Some(kind) = error_kind {
+            *self.errors.write().entry(kind).or_default() += 1;
+        }
+        let mut recent = self.recent.write();
+        if recent.len() == RECENT_REQUESTS {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// Recent requests, newest first
+    pub fn recent(&self) -> Vec<RequestRecord> {
+        self.recent.read().iter().rev().cloned().collect()
+    }
+
+    pub fn errors(&self) -> BTreeMap<&'static str, u64> {
+        self.errors.read().clone()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
@@ -1,9 +1,11 @@
 use itertools::Itertools;
 use serde::Serialize;
-use std::fmt::Write;
+use std::{collections::HashMap, fmt::Write};
 
 use crate::{
-    types::message::{ContentBlock, ImageSource, Message, MessageContent, Role},
+    client::Upload,
+    tools::{render_call, render_result},
+    types::message::{ContentBlock, DocumentSource, Message, MessageContent, Role},
     utils::print_out_text,
 };
 
@@ -11,8 +13,31 @@ use crate::{
 pub struct Merged {
     pub head: String,
     pub tail: String,
+    /// Images and PDFs, in the order they appear
     #[serde(skip)]
-    pub images: Vec<ImageSource>,
+    pub uploads: Vec<Upload>,
+    /// Text documents, sent as attachments
+    #[serde(skip)]
+    pub documents: Vec<TextDocument>,
+}
+
+#[derive(Debug)]
+pub struct TextDocument {
+    pub file_name: String,
+    pub content: String,
+}
+
+impl TextDocument {
+    fn new(title: Option<String>, context: Option<String>, text: String) -> Self {
+        let content = match context {
+            Some(context) => format!("{}\n\n{}", context, text),
+            None => text,
+        };
+        Self {
+            file_name: title.unwrap_or_else(|| "document.txt".to_string()),
+            content,
+        }
+    }
 }
 
 pub fn merge_messages(msgs: Vec<Message>, user_real_roles: bool) -> Option<Merged> {
@@ -22,21 +47,61 @@ pub fn merge_messages(msgs: Vec<Message>, user_real_roles: bool) -> Option<Merge
     }
     let size = size_of_val(&msgs);
     let mut w = String::with_capacity(size);
-    let mut imgs: Vec<ImageSource> = vec![];
+    let mut uploads: Vec<Upload> = vec![];
+    let mut documents: Vec<TextDocument> = vec![];
+    let (mut images, mut docs) = (0, 0);
+    // tool names by call id, for the results
+    let mut calls: HashMap<String, String> = HashMap::new();
 
     let chunks = msgs
         .into_iter()
-        .map_while(|m| match m.content {
+        .filter_map(|m| match m.content {
             MessageContent::Blocks { content } => {
                 let blocks = content
                     .into_iter()
-                    .map_while(|b| match b {
+                    .filter_map(|b| match b {
                         ContentBlock::Text { text } => Some(text.trim().to_string()),
                         ContentBlock::Image { source } => {
-                            imgs.push(source);
+                            images += 1;
+                            uploads.push(Upload::image(images, source));
+                            None
+                        }
+                        ContentBlock::Document {
+                            source,
+                            title,
+                            context,
+                        } => {
+                            docs += 1;
+                            match source {
+                                DocumentSource::Base64 { media_type, data } => {
+                                    let pdf =
+                                        Upload::document(docs, title.as_deref(), media_type, data);
+                                    uploads.push(pdf);
+                                }
+                                DocumentSource::Text { data, .. }
+                                | DocumentSource::Content { content: data } => {
+                                    documents.push(TextDocument::new(title, context, data));
+                                }
+                            }
+                            None
+                        }
+                        ContentBlock::ToolUse { id, name, input } => {
+                            let call = render_call(&name, &input);
+                            calls.insert(id, name);
+                            Some(call)
+                        }
+                        ContentBlock::ToolResult {
+                            tool_use_id,
+                            content,
+                            is_error,
+                        } => {
+                            let name = calls.get(&tool_use_id).map_or("", |n| n.as_str());
+                            Some(render_result(name, &content, is_error.unwrap_or(false)))
+                        }
+                        // claude.ai can't take earlier thinking back
+                        ContentBlock::Thinking { .. } | ContentBlock::RedactedThinking { .. } => {
                             None
                         }
-                        _ => None,
                     })
                     .collect::<Vec<_>>()
                     .join("\n");
@@ -57,11 +122,20 @@ pub fn merge_messages(msgs: Vec<Message>, user_real_roles: bool) -> Option<Merge
         })
         .chunk_by(|m| m.0.clone());
     // merge same role
-    let mut msgs = chunks.into_iter().map(|(role, grp)| {
-        let txt = grp.into_iter().map(|m| m.1).collect::<Vec<_>>().join("\n");
-        (role, txt)
-    });
-    let first = msgs.next()?;
+    let mut msgs = chunks
+        .into_iter()
+        .map(|(role, grp)| {
+            let txt = grp.into_iter().map(|m| m.1).collect::<Vec<_>>().join("\n");
+            (role, txt)
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+    // a request of files only still has something to send
+    let first = match msgs.next() {
+        Some(first) => first,
+        None if uploads.is_empty() && documents.is_empty() => return None,
+        None => (Role::User, String::new()),
+    };
     // first message does not need prefix
     for (role, text) in msgs {
         let prefix = match role {
@@ -76,6 +150,7 @@ pub fn merge_messages(msgs: Vec<Message>, user_real_roles: bool) -> Option<Merge
     Some(Merged {
         head: first.1,
         tail: w,
-        images: imgs,
+        uploads,
+        documents,
     })
 }
@@ -0,0 +1,212 @@
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde_json::{Value, json};
+use tracing::debug;
+
+use crate::{
+    config::ThinkingMode,
+    sse::{EventRewriter, event, event_data},
+    tokenizer::{count_tokens, truncate_to_tokens},
+};
+
+/// Marks the placeholder signatures, they are base64 of this and a random UUID
+const PLACEHOLDER_TAG: &[u8] = b"clewdr";
+
+/// Whether `signature` is a placeholder made by [`ThinkingFilter`]
+pub fn is_placeholder(signature: &str) -> bool {
+    BASE64_STANDARD
+        .decode(signature)
+        .is_ok_and(|s| s.starts_with(PLACEHOLDER_TAG))
+}
+
+/// Remove thinking blocks with a placeholder signature from the messages of `body`
+///
+/// The official API checks signatures and rejects a request echoing such a
+/// block, so thinking written through claude.ai is lost when the conversation
+/// moves to the API. Assistant turns left empty are removed as well.
+pub fn strip_placeholder_thinking(body: &mut Value) {
+    let Some(messages) = body.get_mut("messages").and_then(|m| m.as_array_mut()) else {
+        return;
+    };
+    let mut stripped = 0;
+    for content in messages
+        .iter_mut()
+        .filter_map(|m| m.get_mut("content").and_then(|c| c.as_array_mut()))
+    {
+        let len = content.len();
+        content.retain(|b| {
+            b["type"] != "thinking" || !b["signature"].as_str().is_some_and(is_placeholder)
+        });
+        stripped += len - content.len();
+    }
+    if stripped == 0 {
+        return;
+    }
+    messages.retain(|m| m["content"].as_array().is_none_or(|c| !c.is_empty()));
+    debug!("Removed {} thinking block(s) signed by clewdr", stripped);
+}
+
+/// Normalizes the thinking claude.ai streams in extended mode
+///
+/// Thinking blocks get `thinking_delta` events and end with a
+/// `signature_delta`, a placeholder when claude.ai sends none, see
+/// [`strip_placeholder_thinking`]. Thinking past
+/// `budget` tokens is dropped. With `ThinkingMode::Strip` thinking blocks are
+/// removed and the following blocks renumbered, with `ThinkingMode::Inline`
+/// they become text blocks wrapped in `<thinking>` tags.
+pub struct ThinkingFilter {
+    mode: ThinkingMode,
+    budget: u32,
+    /// Thinking tokens sent so far
+    spent: u32,
+    /// Upstream index of the thinking block in progress
+    current: Option<u64>,
+    /// Whether upstream signed the block in progress
+    signed: bool,
+    /// Upstream indexes of the stripped blocks
+    stripped: Vec<u64>,
+}
+
+impl ThinkingFilter {
+    pub fn new(mode: ThinkingMode, budget: u32) -> Self {
+        Self {
+            mode,
+            budget,
+            spent: 0,
+            current: None,
+            signed: false,
+            stripped: vec![],
+        }
+    }
+
+    /// Index of upstream block `index` once stripped blocks are removed
+    fn index(&self, index: u64) -> u64 {
+        index - self.stripped.iter().filter(|i| **i < index).count() as u64
+    }
+
+    /// Thinking text within the budget
+    fn spend(&mut self, thinking: &str) -> Option<String> {
+        let left = self.budget.saturating_sub(self.spent);
+        if left == 0 {
+            return None;
+        }
+        let thinking = truncate_to_tokens(thinking, left);
+        self.spent += count_tokens(&thinking).min(left);
+        Some(thinking)
+    }
+
+    fn delta(&self, index: u64, delta: Value) -> Vec<String> {
+        if self.mode == ThinkingMode::Strip {
+            return vec![];
+        }
+        vec![event(&json!({
+            "type": "content_block_delta",
+            "index": self.index(index),
+            "delta": delta,
+        }))]
+    }
+
+    fn thinking(&self, index: u64, thinking: String) -> Vec<String> {
+        match self.mode {
+            ThinkingMode::Inline => {
+                self.delta(index, json!({"type": "text_delta", "text": thinking}))
+            }
+            _ => self.delta(
+                index,
+                json!({"type": "thinking_delta", "thinking": thinking}),
+            ),
+        }
+    }
+
+    fn start(&mut self, index: u64) -> Vec<String> {
+        self.current = Some(index);
+        self.signed = false;
+        let block = match self.mode {
+            ThinkingMode::Strip => {
+                self.stripped.push(index);
+                return vec![];
+            }
+            ThinkingMode::Inline => json!({"type": "text", "text": ""}),
+            ThinkingMode::Keep => json!({"type": "thinking", "thinking": ""}),
+        };
+        let mut out = vec![event(&json!({
+            "type": "content_block_start",
+            "index": self.index(index),
+            "content_block": block,
+        }))];
+        if self.mode == ThinkingMode::Inline {
+            out.extend(self.delta(index, json!({"type": "text_delta", "text": "<thinking>\n"})));
+        }
+        out
+    }
+
+    fn stop(&mut self, index: u64) -> Vec<String> {
+        self.current = None;
+        let mut out = match self.mode {
+            ThinkingMode::Strip => return vec![],
+            ThinkingMode::Inline => self.delta(
+                index,
+                json!({"type": "text_delta", "text": "\n</thinking>\n\n"}),
+            ),
+            ThinkingMode::Keep if !self.signed => {
+                // claude.ai does not sign thinking, clients only echo it back
+                let mut signature = PLACEHOLDER_TAG.to_vec();
+                signature.extend(uuid::Uuid::new_v4().as_bytes());
+                let signature = BASE64_STANDARD.encode(signature);
+                self.delta(
+                    index,
+                    json!({"type": "signature_delta", "signature": signature}),
+                )
+            }
+            ThinkingMode::Keep => vec![],
+        };
+        out.push(event(&json!({
+            "type": "content_block_stop",
+            "index": self.index(index),
+        })));
+        out
+    }
+}
+
+impl EventRewriter for ThinkingFilter {
+    fn rewrite(&mut self, raw: &str) -> Vec<String> {
+        let Some(mut value) = event_data(raw) else {
+            return vec![raw.to_string()];
+        };
+        let Some(index) = value["index"].as_u64() else {
+            return vec![raw.to_string()];
+        };
+        let in_thinking = self.current == Some(index);
+        match value["type"].as_str() {
+            Some("content_block_start") if value["content_block"]["type"] == "thinking" => {
+                return self.start(index);
+            }
+            Some("content_block_delta") if in_thinking => {
+                let delta = value["delta"].take();
+                return match delta["type"].as_str() {
+                    Some("thinking_delta") => {
+                        let thinking = delta["thinking"].as_str().unwrap_or_default();
+                        match self.spend(thinking) {
+                            Some(thinking) => self.thinking(index, thinking),
+                            None => vec![],
+                        }
+                    }
+                    Some("signature_delta") if self.mode == ThinkingMode::Keep => {
+                        self.signed = true;
+                        self.delta(index, delta)
+                    }
+                    _ => {
+                        debug!("Dropping thinking delta: {}", delta);
+                        vec![]
+                    }
+                };
+            }
+            Some("content_block_stop") if in_thinking => return self.stop(index),
+            _ => {}
+        }
+        if self.stripped.is_empty() {
+            return vec![raw.to_string()];
+        }
+        value["index"] = self.index(index).into();
+        vec![event(&value)]
+    }
+}
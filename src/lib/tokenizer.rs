@@ -0,0 +1,25 @@
+use tiktoken_rs::cl100k_base_singleton;
+
+/// Number of tokens in `text`
+///
+/// Claude's own tokenizer is not published, `cl100k_base` is bundled instead,
+/// so counts are estimates and can differ from what Claude reports.
+pub fn count_tokens(text: &str) -> u32 {
+    cl100k_base_singleton()
+        .encode_with_special_tokens(text)
+        .len() as u32
+}
+
+/// The longest prefix of `text` that is at most `max` tokens
+pub fn truncate_to_tokens(text: &str, max: u32) -> String {
+    let bpe = cl100k_base_singleton();
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max as usize {
+        return text.to_string();
+    }
+    // a token may end inside a character, drop it until the rest decodes
+    (0..=max as usize)
+        .rev()
+        .find_map(|n| bpe.decode(tokens[..n].to_vec()).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,293 @@
+use axum::body::Bytes;
+use futures::Stream;
+use regex::Regex;
+use serde_json::{Value, json};
+use std::sync::LazyLock;
+use tracing::warn;
+
+use crate::{
+    sse::{EventRewriter, event, event_data, rewrite_events},
+    types::message::{Tool, ToolChoice},
+};
+
+const CALLS_OPEN: &str = "<function_calls>";
+const CALLS_CLOSE: &str = "</function_calls>";
+
+static INVOKE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?s)<invoke name="([^"]+)">(.*?)</invoke>"#).unwrap());
+static PARAMETER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?s)<parameter name="([^"]+)">(.*?)</parameter>"#).unwrap());
+
+/// Prompt section describing `tools` and how to call them, `None` without tools
+pub fn render_tools(tools: &[Tool], choice: Option<&ToolChoice>) -> Option<String> {
+    if tools.is_empty() || matches!(choice, Some(ToolChoice::None)) {
+        return None;
+    }
+    let functions = tools
+        .iter()
+        .map(|t| {
+            let function = json!({
+                "name": t.name,
+                "description": t.description,
+                "parameters": t.input_schema,
+            });
+            format!("<function>{}</function>", function)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut prompt = format!(
+        "In this environment you have access to a set of tools you can use to answer the user's question.\n\
+        You can invoke functions by writing a \"{CALLS_OPEN}\" block like the following as part of your reply to the user:\n\
+        {CALLS_OPEN}\n\
+        <invoke name=\"$FUNCTION_NAME\">\n\
+        <parameter name=\"$PARAMETER_NAME\">$PARAMETER_VALUE</parameter>\n\
+        ...\n\
+        </invoke>\n\
+        {CALLS_CLOSE}\n\n\
+        String and scalar parameters should be specified as is, while lists and objects should use JSON format.\n\
+        Results are returned in a \"<function_results>\" block in the next turn.\n\n\
+        Here are the functions available in JSONSchema format:\n\
+        <functions>\n{functions}\n</functions>"
+    );
+    match choice {
+        Some(ToolChoice::Any) => {
+            prompt.push_str("\n\nYou must call at least one of these functions in your reply.")
+        }
+        Some(ToolChoice::Tool { name }) => prompt.push_str(&format!(
+            "\n\nYou must call the function {} in your reply.",
+            name
+        )),
+        _ => {}
+    }
+    Some(prompt)
+}
+
+/// A previous `tool_use` block, as the model would have written it
+pub fn render_call(name: &str, input: &Value) -> String {
+    let parameters = input
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(k, v)| {
+            let v = match v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            format!("<parameter name=\"{}\">{}</parameter>\n", k, v)
+        })
+        .collect::<String>();
+    format!(
+        "{CALLS_OPEN}\n<invoke name=\"{}\">\n{}</invoke>\n{CALLS_CLOSE}",
+        name, parameters
+    )
+}
+
+/// A `tool_result` block, answering a call to `name`
+pub fn render_result(name: &str, content: &str, is_error: bool) -> String {
+    if is_error {
+        return format!(
+            "<function_results>\n<error>\n{}\n</error>\n</function_results>",
+            content
+        );
+    }
+    format!(
+        "<function_results>\n<result>\n<tool_name>{}</tool_name>\n<stdout>\n{}\n</stdout>\n</result>\n</function_results>",
+        name, content
+    )
+}
+
+/// Calls in a `<function_calls>` block, with inputs typed after the tool schemas
+///
+/// Calls to tools missing from `tools` are left out.
+fn parse_calls(xml: &str, tools: &[Tool]) -> Vec<(String, Value)> {
+    INVOKE
+        .captures_iter(xml)
+        .filter_map(|invoke| {
+            let name = invoke[1].to_string();
+            let schema = &tools.iter().find(|t| t.name == name)?.input_schema["properties"];
+            let input = PARAMETER
+                .captures_iter(&invoke[2])
+                .map(|p| {
+                    let raw = p[2].trim_matches('\n');
+                    let is_string = schema[&p[1]]["type"] == "string";
+                    let value = match serde_json::from_str::<Value>(raw) {
+                        Ok(v) if !is_string => v,
+                        _ => Value::String(raw.to_string()),
+                    };
+                    (p[1].to_string(), value)
+                })
+                .collect::<serde_json::Map<_, _>>();
+            Some((name, Value::Object(input)))
+        })
+        .collect()
+}
+
+/// Turn `<function_calls>` written by the model into `tool_use` content blocks
+///
+/// Text is passed through until a call block starts. Once it is complete,
+/// the text block is closed, each call becomes a `tool_use` block with one
+/// `input_json_delta`, the message ends with `stop_reason: "tool_use"` and
+/// the upstream stream is dropped, calling `on_call`.
+pub fn tool_stream<S, E>(
+    stream: S,
+    tools: Vec<Tool>,
+    on_call: impl FnOnce() + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let parser = ToolParser {
+        tools,
+        pending: String::new(),
+        in_call: false,
+        next_index: 0,
+        stopped: false,
+        on_call: Some(Box::new(on_call)),
+    };
+    rewrite_events(stream, parser)
+}
+
+struct ToolParser {
+    tools: Vec<Tool>,
+    /// Text held back, a call block or what may be the start of one
+    pending: String,
+    /// Whether `pending` starts with `CALLS_OPEN`
+    in_call: bool,
+    /// Index for the next content block
+    next_index: u64,
+    /// Whether the calls were sent, the rest of upstream is discarded
+    stopped: bool,
+    on_call: Option<Box<dyn FnOnce() + Send>>,
+}
+
+fn text_delta(index: u64, text: &str) -> String {
+    event(&json!({
+        "type": "content_block_delta",
+        "index": index,
+        "delta": {"type": "text_delta", "text": text},
+    }))
+}
+
+impl EventRewriter for ToolParser {
+    fn rewrite(&mut self, raw: &str) -> Vec<String> {
+        let Some(value) = event_data(raw) else {
+            return vec![raw.to_string()];
+        };
+        let index = value["index"].as_u64().unwrap_or_default();
+        match value["type"].as_str() {
+            Some("content_block_start") => {
+                self.next_index = self.next_index.max(index + 1);
+                vec![raw.to_string()]
+            }
+            Some("content_block_delta") => match value["delta"]["text"].as_str() {
+                Some(text) => {
+                    self.pending.push_str(text);
+                    self.scan(index)
+                }
+                None => vec![raw.to_string()],
+            },
+            Some("content_block_stop") => {
+                // the model may end without closing the block
+                if self.in_call && !parse_calls(&self.pending, &self.tools).is_empty() {
+                    return self.calls(index);
+                }
+                let mut out = vec![];
+                if !self.pending.is_empty() {
+                    out.push(text_delta(index, &std::mem::take(&mut self.pending)));
+                    self.in_call = false;
+                }
+                out.push(raw.to_string());
+                out
+            }
+            _ => vec![raw.to_string()],
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+impl ToolParser {
+    /// Pass on the text in `pending` that can't be part of a call block
+    fn scan(&mut self, index: u64) -> Vec<String> {
+        if !self.in_call {
+            let keep = match self.pending.find(CALLS_OPEN) {
+                Some(start) => {
+                    self.in_call = true;
+                    start
+                }
+                // hold back what may be the start of `CALLS_OPEN`
+                None => (1..CALLS_OPEN.len())
+                    .rev()
+                    .find(|n| self.pending.ends_with(&CALLS_OPEN[..*n]))
+                    .map_or(self.pending.len(), |n| self.pending.len() - n),
+            };
+            let text = self.pending.drain(..keep).collect::<String>();
+            let mut out = vec![];
+            if !text.is_empty() {
+                out.push(text_delta(index, &text));
+            }
+            if self.in_call {
+                out.extend(self.scan(index));
+            }
+            return out;
+        }
+        let Some(end) = self.pending.find(CALLS_CLOSE) else {
+            return vec![];
+        };
+        if !parse_calls(&self.pending, &self.tools).is_empty() {
+            return self.calls(index);
+        }
+        warn!("No valid call in function_calls block, sending it as text");
+        self.in_call = false;
+        let text = self
+            .pending
+            .drain(..end + CALLS_CLOSE.len())
+            .collect::<String>();
+        let mut out = vec![text_delta(index, &text)];
+        out.extend(self.scan(index));
+        out
+    }
+
+    /// Close text block `index` and send the calls in `pending` as `tool_use` blocks
+    fn calls(&mut self, index: u64) -> Vec<String> {
+        let calls = parse_calls(&self.pending, &self.tools);
+        let unknown = INVOKE.captures_iter(&self.pending).count() - calls.len();
+        if unknown > 0 {
+            warn!("Dropped {} call(s) to unknown tools", unknown);
+        }
+        let mut out = vec![event(
+            &json!({"type": "content_block_stop", "index": index}),
+        )];
+        for (name, input) in calls {
+            let index = self.next_index;
+            self.next_index += 1;
+            let id = format!("toolu_{}", uuid::Uuid::new_v4().simple());
+            out.push(event(&json!({
+                "type": "content_block_start",
+                "index": index,
+                "content_block": {"type": "tool_use", "id": id, "name": name, "input": {}},
+            })));
+            out.push(event(&json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "input_json_delta", "partial_json": input.to_string()},
+            })));
+            out.push(event(
+                &json!({"type": "content_block_stop", "index": index}),
+            ));
+        }
+        out.push(event(&json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": "tool_use", "stop_sequence": null},
+        })));
+        out.push(event(&json!({"type": "message_stop"})));
+        self.pending.clear();
+        self.stopped = true;
+        if let Some(on_call) = self.on_call.take() {
+            on_call();
+        }
+        out
+    }
+}
@@ -185,6 +185,16 @@ pub enum ContentBlock {
     /// Image content
     #[serde(rename = "image")]
     Image { source: ImageSource },
+    /// Document content, a PDF or text
+    #[serde(rename = "document")]
+    Document {
+        source: DocumentSource,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        /// About the document, given to the model along with it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<String>,
+    },
     /// Tool use content
     #[serde(rename = "tool_use")]
     ToolUse {
@@ -192,24 +202,77 @@ pub enum ContentBlock {
         name: String,
         input: serde_json::Value,
     },
+    /// Extended thinking content
+    #[serde(rename = "thinking")]
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: String,
+    },
+    /// Thinking flagged by safety systems, encrypted
+    #[serde(rename = "redacted_thinking")]
+    RedactedThinking { data: String },
     /// Tool result content
     #[serde(rename = "tool_result")]
     ToolResult {
         tool_use_id: String,
+        /// Text blocks of the result are joined
+        #[serde(default, deserialize_with = "text_or_blocks")]
         content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
     },
 }
 
+/// A string, or the text of an array of content blocks
+fn text_or_blocks<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => s,
+        serde_json::Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|b| b["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    })
+}
+
 /// Source of an image
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct ImageSource {
-    /// Type of image source
+    /// Type of image source, `base64` or `url`
     #[serde(rename = "type")]
     pub type_: String,
     /// Media type of the image
+    #[serde(default)]
     pub media_type: String,
     /// Base64-encoded image data
+    #[serde(default)]
     pub data: String,
+    /// Where to fetch the image from, for `url` sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Source of a document
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum DocumentSource {
+    /// Base64-encoded PDF
+    #[serde(rename = "base64")]
+    Base64 { media_type: String, data: String },
+    /// Plain text
+    #[serde(rename = "text")]
+    Text { media_type: String, data: String },
+    /// Content blocks, their text is joined
+    #[serde(rename = "content")]
+    Content {
+        #[serde(deserialize_with = "text_or_blocks")]
+        content: String,
+    },
 }
 
 /// Tool definition
@@ -237,6 +300,9 @@ pub enum ToolChoice {
     /// Model must use a specific tool
     #[serde(rename = "tool")]
     Tool { name: String },
+    /// Model must not use tools
+    #[serde(rename = "none")]
+    None,
 }
 
 /// Message metadata
@@ -280,7 +346,7 @@ pub enum StopReason {
 }
 
 /// Token usage statistics
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
 pub struct Usage {
     /// Input tokens used
     pub input_tokens: u32,
@@ -335,18 +401,37 @@ impl ContentBlock {
                 type_: type_.into(),
                 media_type: media_type.into(),
                 data: data.into(),
+                url: None,
+            },
+        }
+    }
+
+    /// Create a new image block fetched from `url`
+    pub fn image_url(url: impl Into<String>) -> Self {
+        Self::Image {
+            source: ImageSource {
+                type_: "url".to_string(),
+                media_type: String::new(),
+                data: String::new(),
+                url: Some(url.into()),
             },
         }
     }
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct CountMessageTokensParams {
     pub model: String,
     pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CountMessageTokensResponse {
     pub input_tokens: u32,
 }
@@ -387,10 +472,15 @@ pub struct MessageStartContent {
     #[serde(rename = "type")]
     pub type_: String,
     pub role: Role,
+    #[serde(default)]
     pub content: Vec<ContentBlock>,
+    /// Empty on claude.ai
+    #[serde(default)]
     pub model: String,
     pub stop_reason: Option<StopReason>,
     pub stop_sequence: Option<String>,
+    /// Not sent by claude.ai
+    #[serde(default)]
     pub usage: Usage,
 }
 
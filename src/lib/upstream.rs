@@ -0,0 +1,227 @@
+use async_trait::async_trait;
+use rquest::{
+    Client, RequestBuilder, Response,
+    header::ACCEPT,
+    multipart::{Form, Part},
+};
+use serde_json::Value;
+use std::{ops::Deref, sync::Arc};
+
+use crate::{
+    client::{AppendHeaders, SUPER_CLIENT},
+    error::ClewdrError,
+};
+
+/// Calls made to claude.ai, or anything that answers like it
+///
+/// `endpoint` is the base url from `Config::endpoint`, it honours `rproxy`.
+/// `cookies` is the `Cookie` header of the current session. Responses are
+/// returned raw, callers pick up `set-cookie` and check errors themselves.
+#[async_trait]
+pub trait Upstream: Send + Sync {
+    /// `GET /api/bootstrap`, account and feature flags
+    async fn bootstrap(&self, endpoint: &str, cookies: &str) -> Result<Response, ClewdrError>;
+
+    /// `GET /api/organizations`
+    async fn organizations(&self, endpoint: &str, cookies: &str) -> Result<Response, ClewdrError>;
+
+    /// `POST /api/account` with new account settings
+    async fn update_account(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        body: &Value,
+    ) -> Result<Response, ClewdrError>;
+
+    /// `POST /api/organizations/{org}/chat_conversations`
+    async fn create_conversation(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        body: &Value,
+    ) -> Result<Response, ClewdrError>;
+
+    /// `POST .../chat_conversations/{conv}/completion`, an event stream
+    async fn completion(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        conv: &str,
+        body: &Value,
+    ) -> Result<Response, ClewdrError>;
+
+    /// `POST /api/{org}/upload` with one file
+    async fn upload(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Response, ClewdrError>;
+
+    /// `DELETE .../chat_conversations/{conv}`
+    async fn delete_conversation(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        conv: &str,
+    ) -> Result<Response, ClewdrError>;
+}
+
+/// Shared upstream held by `AppState`, claude.ai through `SUPER_CLIENT` by default
+#[derive(Clone)]
+pub struct SharedUpstream(pub Arc<dyn Upstream>);
+
+impl Default for SharedUpstream {
+    fn default() -> Self {
+        Self(Arc::new(WebUpstream::default()))
+    }
+}
+
+impl Deref for SharedUpstream {
+    type Target = dyn Upstream;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// claude.ai web API over HTTP
+pub struct WebUpstream {
+    client: Client,
+}
+
+impl Default for WebUpstream {
+    fn default() -> Self {
+        Self::new(SUPER_CLIENT.clone())
+    }
+}
+
+impl WebUpstream {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+fn url(endpoint: &str, path: &str) -> String {
+    format!("{}/{}", endpoint.trim_end_matches('/'), path)
+}
+
+async fn send(req: RequestBuilder) -> Result<Response, ClewdrError> {
+    Ok(req.send().await?)
+}
+
+#[async_trait]
+impl Upstream for WebUpstream {
+    async fn bootstrap(&self, endpoint: &str, cookies: &str) -> Result<Response, ClewdrError> {
+        send(
+            self.client
+                .get(url(endpoint, "api/bootstrap"))
+                .append_headers("", cookies),
+        )
+        .await
+    }
+
+    async fn organizations(&self, endpoint: &str, cookies: &str) -> Result<Response, ClewdrError> {
+        send(
+            self.client
+                .get(url(endpoint, "api/organizations"))
+                .append_headers("", cookies),
+        )
+        .await
+    }
+
+    async fn update_account(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        body: &Value,
+    ) -> Result<Response, ClewdrError> {
+        send(
+            self.client
+                .post(url(endpoint, "api/account"))
+                .append_headers("", cookies)
+                .json(body),
+        )
+        .await
+    }
+
+    async fn create_conversation(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        body: &Value,
+    ) -> Result<Response, ClewdrError> {
+        let path = format!("api/organizations/{}/chat_conversations", org);
+        send(
+            self.client
+                .post(url(endpoint, &path))
+                .append_headers("", cookies)
+                .json(body),
+        )
+        .await
+    }
+
+    async fn completion(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        conv: &str,
+        body: &Value,
+    ) -> Result<Response, ClewdrError> {
+        let path = format!(
+            "api/organizations/{}/chat_conversations/{}/completion",
+            org, conv
+        );
+        send(
+            self.client
+                .post(url(endpoint, &path))
+                .append_headers("", cookies)
+                .header_append(ACCEPT, "text/event-stream")
+                .json(body),
+        )
+        .await
+    }
+
+    async fn upload(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        file_name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Response, ClewdrError> {
+        let part = Part::bytes(bytes).file_name(file_name.to_string());
+        let form = Form::new().part("file", part);
+        send(
+            self.client
+                .post(url(endpoint, &format!("api/{}/upload", org)))
+                .append_headers("new", cookies)
+                .header_append("anthropic-client-platform", "web_claude_ai")
+                .multipart(form),
+        )
+        .await
+    }
+
+    async fn delete_conversation(
+        &self,
+        endpoint: &str,
+        cookies: &str,
+        org: &str,
+        conv: &str,
+    ) -> Result<Response, ClewdrError> {
+        let path = format!("api/organizations/{}/chat_conversations/{}", org, conv);
+        send(
+            self.client
+                .delete(url(endpoint, &path))
+                .append_headers("", cookies),
+        )
+        .await
+    }
+}
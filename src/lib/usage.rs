@@ -0,0 +1,388 @@
+use axum::{
+    body::{Body, Bytes},
+    http::header::CONTENT_TYPE,
+    response::Response,
+};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::{
+    config::Cookie,
+    sse::{self, EventRewriter, event_data, rewrite_events},
+    state::AppState,
+    tokenizer::{count_tokens, truncate_to_tokens},
+    types::message::Usage,
+};
+
+/// File next to the config where usage totals are kept
+pub const USAGE_FILE: &str = "usage.toml";
+
+/// Client name of requests without a key from `[[clients]]`
+pub const ANONYMOUS_CLIENT: &str = "anonymous";
+
+/// Accumulated usage of one cookie or client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl TokenUsage {
+    fn add(&mut self, usage: &Usage) {
+        self.requests += 1;
+        self.input_tokens += usage.input_tokens as u64;
+        self.output_tokens += usage.output_tokens as u64;
+    }
+}
+
+/// Tokens a client used on one UTC day, for its `daily_tokens` limit
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    /// `YYYY-MM-DD`
+    pub date: String,
+    pub tokens: u64,
+}
+
+/// Usage totals per cookie and per client, persisted across restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageTotals {
+    #[serde(default)]
+    pub cookies: BTreeMap<Cookie, TokenUsage>,
+    /// By client name, see `clients::client_name`
+    #[serde(default)]
+    pub clients: BTreeMap<String, TokenUsage>,
+    #[serde(default)]
+    pub daily: BTreeMap<String, DailyUsage>,
+}
+
+impl UsageTotals {
+    /// Read the totals from `path`, starting from zero if it is missing or invalid
+    pub fn load(path: &Path) -> Self {
+        let Ok(content) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        toml::from_str(&content).unwrap_or_else(|e| {
+            warn!("Invalid {}, starting from zero: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Tokens `client` used so far today
+    pub fn tokens_today(&self, client: &str) -> u64 {
+        self.daily
+            .get(client)
+            .filter(|d| d.date == today())
+            .map_or(0, |d| d.tokens)
+    }
+}
+
+fn today() -> String {
+    chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+impl AppState {
+    /// Where usage totals are saved, next to the config file
+    pub fn usage_path(&self) -> Option<PathBuf> {
+        let path = self.0.config.read().file_path().ok()?;
+        Some(path.with_file_name(USAGE_FILE))
+    }
+
+    pub fn record_cookie_usage(&self, cookie: Cookie, usage: &Usage) {
+        self.0
+            .usage
+            .write()
+            .cookies
+            .entry(cookie)
+            .or_default()
+            .add(usage);
+        self.save_usage();
+    }
+
+    pub fn record_client_usage(&self, client: &str, usage: &Usage) {
+        {
+            let mut totals = self.0.usage.write();
+            totals
+                .clients
+                .entry(client.to_string())
+                .or_default()
+                .add(usage);
+            let today = today();
+            let daily = totals.daily.entry(client.to_string()).or_default();
+            if daily.date != today {
+                *daily = DailyUsage {
+                    date: today,
+                    tokens: 0,
+                };
+            }
+            daily.tokens += (usage.input_tokens + usage.output_tokens) as u64;
+        }
+        self.save_usage();
+    }
+
+    fn save_usage(&self) {
+        let Some(path) = self.usage_path() else {
+            return;
+        };
+        let persister = self.0.config.read().persister().clone();
+        match toml::to_string_pretty(&*self.0.usage.read()) {
+            Ok(content) => persister.schedule_write(path, content, 0),
+            Err(e) => warn!("Failed to serialize usage: {}", e),
+        }
+    }
+}
+
+/// Fill in and account for the `usage` of a successful `/v1/messages` response
+///
+/// Event streams get estimated `usage` in `message_start` and `message_delta`
+/// where upstream sent none, JSON bodies are only read. `on_done` receives the
+/// final usage once the body has been consumed or dropped.
+pub async fn meter_response(
+    res: Response,
+    input_tokens: u32,
+    on_done: impl FnOnce(Usage) + Send + 'static,
+) -> Response {
+    if !res.status().is_success() {
+        return res;
+    }
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with("application/json"));
+    let (parts, body) = res.into_parts();
+    if !is_json {
+        let stream = meter_stream(body.into_data_stream(), input_tokens, None, on_done);
+        let body = Body::from_stream(stream);
+        return Response::from_parts(parts, body);
+    }
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Failed to read response body: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let usage = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|v| serde_json::from_value::<Usage>(v["usage"].clone()).ok())
+        .unwrap_or(Usage {
+            input_tokens,
+            output_tokens: 0,
+        });
+    on_done(usage);
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Where to cut an event stream off, see `meter_stream`
+pub struct StreamLimit {
+    max_tokens: u32,
+    on_limit: Box<dyn FnOnce() + Send>,
+}
+
+impl StreamLimit {
+    /// Stop after `max_tokens` output tokens, then call `on_limit`
+    pub fn new(max_tokens: u32, on_limit: impl FnOnce() + Send + 'static) -> Self {
+        Self {
+            max_tokens,
+            on_limit: Box::new(on_limit),
+        }
+    }
+}
+
+/// Pass an event stream through, filling in missing `usage`, see `meter_response`
+///
+/// With a `limit`, the delta reaching it is truncated, open content blocks are
+/// closed, the message ends with `stop_reason: "max_tokens"` and the upstream
+/// stream is dropped.
+pub fn meter_stream<S, E>(
+    stream: S,
+    input_tokens: u32,
+    limit: Option<StreamLimit>,
+    on_done: impl FnOnce(Usage) + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let meter = Meter {
+        estimate: input_tokens,
+        input_tokens: None,
+        output_tokens: None,
+        output: String::new(),
+        counted: 0,
+        limit,
+        open_blocks: vec![],
+        stopped: false,
+        on_done: Some(Box::new(on_done)),
+    };
+    rewrite_events(stream, meter)
+}
+
+struct Meter {
+    estimate: u32,
+    /// Usage reported by upstream, if any
+    input_tokens: Option<u32>,
+    output_tokens: Option<u32>,
+    /// Generated text so far
+    output: String,
+    /// Output tokens counted delta by delta, only while limited
+    counted: u32,
+    limit: Option<StreamLimit>,
+    /// Indexes of the content blocks started and not stopped yet
+    open_blocks: Vec<u64>,
+    /// Whether the limit was reached, the rest of upstream is discarded
+    stopped: bool,
+    on_done: Option<Box<dyn FnOnce(Usage) + Send>>,
+}
+
+impl EventRewriter for Meter {
+    fn rewrite(&mut self, event: &str) -> Vec<String> {
+        vec![self.rewrite_event(event)]
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+impl Meter {
+    fn rewrite_event(&mut self, event: &str) -> String {
+        let Some(mut value) = event_data(event) else {
+            return event.to_string();
+        };
+        match value["type"].as_str() {
+            Some("message_start") => {
+                let Some(message) = value["message"].as_object_mut() else {
+                    return event.to_string();
+                };
+                match message
+                    .get("usage")
+                    .and_then(|u| u["input_tokens"].as_u64())
+                {
+                    Some(n) => {
+                        self.input_tokens = Some(n as u32);
+                        return event.to_string();
+                    }
+                    None => {
+                        message.insert(
+                            "usage".to_string(),
+                            json!({"input_tokens": self.estimate, "output_tokens": 0}),
+                        );
+                    }
+                }
+            }
+            Some("content_block_start") => {
+                self.open_blocks.extend(value["index"].as_u64());
+                return event.to_string();
+            }
+            Some("content_block_stop") => {
+                self.open_blocks
+                    .retain(|i| Some(*i) != value["index"].as_u64());
+                return event.to_string();
+            }
+            Some("content_block_delta") => {
+                if !self.limit_delta(&mut value["delta"]) {
+                    return event.to_string();
+                }
+            }
+            Some("message_delta") => match value["usage"]["output_tokens"].as_u64() {
+                Some(n) => {
+                    self.output_tokens = Some(n as u32);
+                    return event.to_string();
+                }
+                None => {
+                    value["usage"] = json!({"output_tokens": count_tokens(&self.output)});
+                }
+            },
+            _ => return event.to_string(),
+        }
+        let event = event
+            .lines()
+            .map(|l| {
+                if l.starts_with("data:") {
+                    format!("data: {}", value)
+                } else {
+                    l.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !self.stopped {
+            return event;
+        }
+        format!("{}\n\n{}", event, self.closing_events())
+    }
+
+    /// Record the text of a delta, truncating it at the limit
+    ///
+    /// Returns whether the limit was reached and the delta changed.
+    fn limit_delta(&mut self, delta: &mut Value) -> bool {
+        for field in ["text", "thinking", "partial_json"] {
+            let Some(text) = delta[field].as_str() else {
+                continue;
+            };
+            let Some(max) = self.limit.as_ref().map(|l| l.max_tokens) else {
+                self.output.push_str(text);
+                continue;
+            };
+            let tokens = count_tokens(text);
+            if self.counted + tokens <= max {
+                self.counted += tokens;
+                self.output.push_str(text);
+                continue;
+            }
+            let text = truncate_to_tokens(text, max - self.counted);
+            self.output.push_str(&text);
+            delta[field] = text.into();
+            self.counted = max;
+            self.stopped = true;
+            if let Some(limit) = self.limit.take() {
+                (limit.on_limit)();
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Events ending the message once the limit is reached
+    fn closing_events(&mut self) -> String {
+        let mut events = std::mem::take(&mut self.open_blocks)
+            .into_iter()
+            .map(|index| json!({"type": "content_block_stop", "index": index}))
+            .collect::<Vec<_>>();
+        events.push(json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": "max_tokens", "stop_sequence": null},
+            "usage": {"output_tokens": count_tokens(&self.output)},
+        }));
+        events.push(json!({"type": "message_stop"}));
+        events
+            .iter()
+            .map(sse::event)
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            input_tokens: self.input_tokens.unwrap_or(self.estimate),
+            output_tokens: self
+                .output_tokens
+                .unwrap_or_else(|| count_tokens(&self.output)),
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.usage());
+        }
+    }
+}
@@ -190,17 +190,4 @@ pub static BANNER: LazyLock<String> = LazyLock::new(|| {
     )
 });
 
-pub const MODELS: [&str; 10] = [
-    "claude-3-7-sonnet-20250219",
-    "claude-3-5-sonnet-20240620",
-    "claude-3-opus-20240229",
-    "claude-3-sonnet-20240229",
-    "claude-3-haiku-20240307",
-    "claude-2.1",
-    "claude-2.0",
-    "claude-1.3",
-    "claude-instant-1.2",
-    "claude-instant-1.1",
-];
-
 pub const ENDPOINT: &str = "https://api.claude.ai";
@@ -1,4 +1,4 @@
-use clewdr::{self, config::Config, error::ClewdrError, state::AppState, utils::BANNER};
+use clewdr::{self, config::Config, error::ClewdrError, server::ClewdrServer, utils::BANNER};
 use colored::Colorize;
 use const_format::formatc;
 
@@ -27,15 +27,7 @@ async fn main() -> Result<(), ClewdrError> {
         env!("CARGO_PKG_AUTHORS")
     );
     println!("{}", TITLE.blue());
-    println!("Listening on {}", config.address().green());
-    // println!("Config:\n{:?}", config);
     // TODO: Local tunnel
 
-    let state = AppState::new(config);
-    let router = clewdr::router::RouterBuilder::new(state.clone()).build();
-    let addr = state.0.config.read().address().to_string();
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    state.bootstrap().await;
-    axum::serve(listener, router).await?;
-    Ok(())
+    ClewdrServer::new(config).serve().await
 }
@@ -0,0 +1,679 @@
+//! Stand-in for claude.ai and helpers to run clewdr against it
+
+#![allow(dead_code)]
+
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{Path, State},
+    http::{
+        HeaderMap, StatusCode,
+        header::{CONTENT_TYPE, HOST},
+    },
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
+};
+use clewdr::{
+    config::Config, error::ClewdrError, router::RouterBuilder, server::ClewdrServer,
+    state::AppState,
+};
+use parking_lot::Mutex;
+use serde_json::{Value, json};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::{Notify, oneshot},
+    task::JoinHandle,
+    time::sleep,
+};
+
+/// Text of every completion served by the mock
+pub const MOCK_REPLY: &str = "Hello from mock";
+
+/// How an account behaves on the mock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Account {
+    Normal,
+    /// Completion answers 429 with `resetsAt` set to this timestamp
+    RateLimited(i64),
+    /// `consumer_banned` in `active_flags`
+    Banned,
+    /// A restriction flag expiring at this timestamp
+    Restricted(i64),
+    /// `completed_verification_at` is null
+    Unverified,
+    /// `claude_pro` plan, can use any model
+    Pro,
+    /// Free plan whose default model is this one
+    Free(&'static str),
+}
+
+#[derive(Default)]
+pub struct MockState {
+    /// Account by session key, in the order they were registered
+    accounts: Mutex<Vec<(String, Account)>>,
+    /// `METHOD path` of every request received
+    pub requests: Mutex<Vec<String>>,
+    /// File names of uploaded files
+    pub uploads: Mutex<Vec<String>>,
+    /// File names whose upload is rejected
+    pub failing_uploads: Mutex<Vec<String>>,
+    /// Contents of uploaded files
+    pub upload_data: Mutex<Vec<Vec<u8>>>,
+    /// Files served under `/images/`, by name
+    pub images: Mutex<HashMap<String, Vec<u8>>>,
+    /// Body of the last create conversation request
+    pub last_conversation: Mutex<Option<Value>>,
+    /// Body of the last completion request
+    pub last_completion: Mutex<Option<Value>>,
+    /// Body of the last official API messages request
+    pub last_api_request: Mutex<Option<Value>>,
+    /// Text deltas of the completion, `MOCK_REPLY` when empty
+    pub reply: Mutex<Vec<String>>,
+    /// Deltas of a thinking block sent before the text, if any
+    pub thinking: Mutex<Vec<String>>,
+    /// When set, completions fail with this status, as during an outage
+    pub completion_status: Mutex<Option<u16>>,
+    /// `x-api-key` of every official API request
+    pub api_keys_used: Mutex<Vec<String>>,
+    /// When set, completions wait for a notification before answering
+    pub gate: Mutex<Option<Arc<Notify>>>,
+}
+
+impl MockState {
+    /// Index and behaviour of the account behind the `Cookie` header
+    fn account(&self, headers: &HeaderMap) -> Option<(usize, Account)> {
+        let cookies = headers.get("cookie")?.to_str().ok()?;
+        let key = cookies
+            .split(';')
+            .filter_map(|c| c.trim().strip_prefix("sessionKey="))
+            .next()?;
+        let accounts = self.accounts.lock();
+        let i = accounts.iter().position(|(k, _)| k == key)?;
+        Some((i, accounts[i].1))
+    }
+
+    fn log(&self, method: &str, path: String) {
+        self.requests.lock().push(format!("{} {}", method, path));
+    }
+
+    /// Number of received requests whose `METHOD path` starts with `prefix`
+    pub fn count(&self, prefix: &str) -> usize {
+        self.requests
+            .lock()
+            .iter()
+            .filter(|r| r.starts_with(prefix))
+            .count()
+    }
+}
+
+/// Organization uuid of the `i`th account
+pub fn org_uuid(i: usize) -> String {
+    format!("org-{}", i)
+}
+
+/// A session key that passes `Cookie::validate`, unique per `tag`
+/// `admin_password` set by `with_admin`
+pub const ADMIN_PASSWORD: &str = "mock-admin";
+
+/// Config edit enabling the admin API with `ADMIN_PASSWORD`
+pub fn with_admin(table: &mut toml::Table) {
+    table.insert("admin_password".to_string(), ADMIN_PASSWORD.into());
+}
+
+pub fn session_key(tag: char) -> String {
+    format!("sk-ant-sid01-{}-bbbbbbAA", tag.to_string().repeat(86))
+}
+
+pub struct MockServer {
+    pub url: String,
+    pub state: Arc<MockState>,
+}
+
+impl MockServer {
+    /// Serve the mock on a random local port
+    pub async fn start(accounts: Vec<(String, Account)>) -> Self {
+        let state = Arc::new(MockState::default());
+        *state.accounts.lock() = accounts;
+        let router = Router::new()
+            .route("/api/bootstrap", get(bootstrap))
+            .route("/api/organizations", get(organizations))
+            .route("/api/account", post(account))
+            .route(
+                "/api/organizations/{org}/chat_conversations",
+                post(create_conversation),
+            )
+            .route(
+                "/api/organizations/{org}/chat_conversations/{conv}",
+                delete(delete_conversation),
+            )
+            .route(
+                "/api/organizations/{org}/chat_conversations/{conv}/completion",
+                post(completion),
+            )
+            .route("/api/{org}/upload", post(upload))
+            .route("/images/{name}", get(image))
+            .route("/redirect/{name}", get(redirect))
+            // official API, for `api_rproxy`
+            .route("/v1/messages", post(api_messages))
+            .route("/v1/messages/count_tokens", post(api_count_tokens))
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        Self { url, state }
+    }
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({"error": {"type": "permission_error", "message": "Invalid authorization"}})),
+    )
+        .into_response()
+}
+
+async fn bootstrap(State(mock): State<Arc<MockState>>, headers: HeaderMap) -> Response {
+    mock.log("GET", "/api/bootstrap".to_string());
+    let Some((i, account)) = mock.account(&headers) else {
+        return unauthorized();
+    };
+    let verified = (account != Account::Unverified).then_some("2024-01-01T00:00:00Z");
+    let capabilities = match account {
+        Account::Pro => json!(["chat", "claude_pro"]),
+        _ => json!(["chat"]),
+    };
+    let default_model = match account {
+        Account::Free(model) => json!({"value": {"model": model}}),
+        _ => json!({}),
+    };
+    Json(json!({
+        "statsig": {
+            "values": {
+                "dynamic_configs": {
+                    "6zA9wvTedwkzjLxWy9PVe7yydI00XDQ6L5Fejjq/2o8=": default_model,
+                },
+            },
+        },
+        "account": {
+            "email_address": format!("user{}@example.com", i),
+            "completed_verification_at": verified,
+            "settings": {"preview_feature_uses_artifacts": false},
+            "memberships": [{
+                "organization": {
+                    "uuid": org_uuid(i),
+                    "name": format!("user{}@example.com's Organization", i),
+                    "capabilities": capabilities,
+                }
+            }],
+        }
+    }))
+    .into_response()
+}
+
+async fn organizations(State(mock): State<Arc<MockState>>, headers: HeaderMap) -> Response {
+    mock.log("GET", "/api/organizations".to_string());
+    let Some((i, account)) = mock.account(&headers) else {
+        return unauthorized();
+    };
+    let expires = |t: i64| chrono::DateTime::from_timestamp(t, 0).unwrap().to_rfc3339();
+    let week = chrono::Utc::now().timestamp() + 7 * 24 * 3600;
+    let active_flags = match account {
+        Account::Banned => json!([{"type": "consumer_banned", "expires_at": expires(week)}]),
+        Account::Restricted(until) => {
+            json!([{"type": "consumer_restricted_mode", "expires_at": expires(until)}])
+        }
+        _ => json!([]),
+    };
+    Json(json!([{
+        "uuid": org_uuid(i),
+        "capabilities": ["chat"],
+        "active_flags": active_flags,
+    }]))
+    .into_response()
+}
+
+async fn account(State(mock): State<Arc<MockState>>) -> Json<Value> {
+    mock.log("POST", "/api/account".to_string());
+    Json(json!({}))
+}
+
+async fn create_conversation(
+    State(mock): State<Arc<MockState>>,
+    Path(org): Path<String>,
+    Json(body): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    mock.log(
+        "POST",
+        format!("/api/organizations/{}/chat_conversations", org),
+    );
+    let uuid = body["uuid"].clone();
+    *mock.last_conversation.lock() = Some(body);
+    (StatusCode::CREATED, Json(json!({"uuid": uuid})))
+}
+
+async fn delete_conversation(
+    State(mock): State<Arc<MockState>>,
+    Path((org, conv)): Path<(String, String)>,
+) -> StatusCode {
+    mock.log(
+        "DELETE",
+        format!("/api/organizations/{}/chat_conversations/{}", org, conv),
+    );
+    StatusCode::NO_CONTENT
+}
+
+async fn completion(
+    State(mock): State<Arc<MockState>>,
+    Path((org, conv)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    mock.log(
+        "POST",
+        format!(
+            "/api/organizations/{}/chat_conversations/{}/completion",
+            org, conv
+        ),
+    );
+    let Some((i, account)) = mock.account(&headers) else {
+        return unauthorized();
+    };
+    // the session cookie and the org must belong to the same account
+    if org != org_uuid(i) {
+        return unauthorized();
+    }
+    *mock.last_completion.lock() = Some(body);
+    let outage = *mock.completion_status.lock();
+    match outage {
+        Some(529) => {
+            let error = json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}});
+            return (StatusCode::from_u16(529).unwrap(), Json(error)).into_response();
+        }
+        Some(status) => {
+            let status = StatusCode::from_u16(status).unwrap();
+            return (status, "<html>Service Unavailable</html>").into_response();
+        }
+        None => {}
+    }
+    let gate = mock.gate.lock().clone();
+    if let Some(gate) = gate {
+        gate.notified().await;
+    }
+    if let Account::RateLimited(resets_at) = account {
+        let message = json!({"type": "exceeded_limit", "resetsAt": resets_at}).to_string();
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(
+                json!({"type": "error", "error": {"type": "rate_limit_error", "message": message}}),
+            ),
+        )
+            .into_response();
+    }
+    let mut reply = mock.reply.lock().clone();
+    if reply.is_empty() {
+        reply.push(MOCK_REPLY.to_string());
+    }
+    let thinking = mock.thinking.lock().clone();
+    let mut blocks = vec![];
+    if !thinking.is_empty() {
+        blocks.push(json!({"type": "content_block_start", "index": 0, "content_block": {"type": "thinking", "thinking": ""}}));
+        blocks.extend(thinking.iter().map(|thinking| {
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": thinking}})
+        }));
+        blocks.push(json!({"type": "content_block_stop", "index": 0}));
+    }
+    let text = blocks.len().min(1);
+    let deltas = reply.iter().map(|t| {
+        json!({"type": "content_block_delta", "index": text, "delta": {"type": "text_delta", "text": t}})
+    });
+    let events = [
+        json!({"type": "message_start", "message": {"id": "msg_mock", "type": "message", "role": "assistant", "content": []}}),
+    ]
+    .into_iter()
+    .chain(blocks)
+    .chain([json!({"type": "content_block_start", "index": text, "content_block": {"type": "text", "text": ""}})])
+    .chain(deltas)
+    .chain([
+        json!({"type": "content_block_stop", "index": text}),
+        json!({"type": "message_delta", "delta": {"stop_reason": "end_turn", "stop_sequence": null}}),
+        json!({"type": "message_stop"}),
+    ])
+    .collect::<Vec<_>>();
+    let body = events
+        .iter()
+        .map(|e| {
+            format!(
+                "event: {}\r\ndata: {}\r\n\r\n",
+                e["type"].as_str().unwrap(),
+                e
+            )
+        })
+        .collect::<String>();
+    ([(CONTENT_TYPE, "text/event-stream")], body).into_response()
+}
+
+async fn upload(
+    State(mock): State<Arc<MockState>>,
+    Path(org): Path<String>,
+    body: Bytes,
+) -> Response {
+    mock.log("POST", format!("/api/{}/upload", org));
+    let text = String::from_utf8_lossy(&body);
+    let file_name = text
+        .split("filename=\"")
+        .nth(1)
+        .and_then(|s| s.split('"').next())
+        .unwrap_or_default()
+        .to_string();
+    if mock.failing_uploads.lock().contains(&file_name) {
+        let error = json!({"type": "error", "error": {"type": "invalid_request_error", "message": "Invalid file"}});
+        return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+    }
+    // the part's content, between its headers and the closing boundary
+    let find = |from: usize, pat: &[u8]| body[from..].windows(pat.len()).position(|w| w == pat);
+    let start = find(0, b"\r\n\r\n").map_or(0, |i| i + 4);
+    let end = find(start, b"\r\n--").map_or(body.len(), |i| start + i);
+    mock.upload_data.lock().push(body[start..end].to_vec());
+    let mut uploads = mock.uploads.lock();
+    uploads.push(file_name);
+    Json(json!({"file_uuid": format!("file-{}", uploads.len())})).into_response()
+}
+
+async fn image(State(mock): State<Arc<MockState>>, Path(name): Path<String>) -> Response {
+    mock.log("GET", format!("/images/{}", name));
+    match mock.images.lock().get(&name) {
+        Some(bytes) => bytes.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Redirects to `/images/{name}` of this mock, reached through `localhost`
+async fn redirect(
+    State(mock): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Response {
+    mock.log("GET", format!("/redirect/{}", name));
+    let host = headers.get(HOST).and_then(|h| h.to_str().ok()).unwrap();
+    let port = host.rsplit(':').next().unwrap();
+    Redirect::temporary(&format!("http://localhost:{}/images/{}", port, name)).into_response()
+}
+
+/// Key accepted by the official API mock
+pub const MOCK_API_KEY: &str = "sk-ant-api03-mock";
+/// Another key the official API mock accepts
+pub const MOCK_API_KEY_2: &str = "sk-ant-api03-mock-2";
+/// Key the official API mock always answers 429 with `retry-after: 120`
+pub const MOCK_LIMITED_API_KEY: &str = "sk-ant-api03-limited";
+/// Model the official API mock refuses with 403 for every key
+pub const MOCK_FORBIDDEN_MODEL: &str = "claude-forbidden";
+
+fn check_api_key(mock: &MockState, headers: &HeaderMap, path: &str) -> Option<Response> {
+    mock.log("POST", path.to_string());
+    let key = headers
+        .get("x-api-key")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    mock.api_keys_used.lock().push(key.to_string());
+    let (status, error_type, message) = match key {
+        MOCK_API_KEY | MOCK_API_KEY_2 => return None,
+        MOCK_LIMITED_API_KEY => (
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limit_error",
+            "rate limited",
+        ),
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            "authentication_error",
+            "invalid x-api-key",
+        ),
+    };
+    Some(
+        (
+            status,
+            [("retry-after", "120")],
+            Json(json!({"type": "error", "error": {"type": error_type, "message": message}})),
+        )
+            .into_response(),
+    )
+}
+
+async fn api_messages(
+    State(mock): State<Arc<MockState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if let Some(res) = check_api_key(&mock, &headers, "/v1/messages") {
+        return res;
+    }
+    *mock.last_api_request.lock() = Some(body.clone());
+    if body["model"] == MOCK_FORBIDDEN_MODEL {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"type": "error", "error": {"type": "permission_error", "message": "model not allowed"}})),
+        )
+            .into_response();
+    }
+    Json(json!({
+        "id": "msg_api_mock",
+        "type": "message",
+        "role": "assistant",
+        "model": body["model"],
+        "content": [{"type": "text", "text": MOCK_REPLY}],
+        "stop_reason": "end_turn",
+        "stop_sequence": null,
+        "usage": {"input_tokens": 10, "output_tokens": 4},
+    }))
+    .into_response()
+}
+
+async fn api_count_tokens(State(mock): State<Arc<MockState>>, headers: HeaderMap) -> Response {
+    if let Some(res) = check_api_key(&mock, &headers, "/v1/messages/count_tokens") {
+        return res;
+    }
+    Json(json!({"input_tokens": 10})).into_response()
+}
+
+/// clewdr serving on a random local port, talking to a `MockServer`
+pub struct Harness {
+    pub mock: MockServer,
+    pub state: AppState,
+    pub url: String,
+    dir: PathBuf,
+}
+
+impl Harness {
+    /// Start a mock with `accounts` and a clewdr using them as its cookie pool
+    pub async fn start(accounts: Vec<(String, Account)>) -> Self {
+        Self::start_with(accounts, |_| {}).await
+    }
+
+    /// Like `start`, with a chance to edit the config table first
+    pub async fn start_with(
+        accounts: Vec<(String, Account)>,
+        edit: impl FnOnce(&mut toml::Table),
+    ) -> Self {
+        let mock = MockServer::start(accounts.clone()).await;
+        let dir = temp_dir();
+        let state = AppState::new(mock_config(&mock, &accounts, &dir, edit));
+        let router = RouterBuilder::new(state.clone()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        state.bootstrap().await;
+        Self {
+            mock,
+            state,
+            url,
+            dir,
+        }
+    }
+
+    /// Run a `ClewdrServer` instead, stopping it when the sender fires or drops
+    pub async fn serve(
+        accounts: Vec<(String, Account)>,
+    ) -> (
+        Self,
+        oneshot::Sender<()>,
+        JoinHandle<Result<(), ClewdrError>>,
+    ) {
+        let mock = MockServer::start(accounts.clone()).await;
+        let dir = temp_dir();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = ClewdrServer::new(mock_config(&mock, &accounts, &dir, |_| {}))
+            .with_listener(listener)
+            .with_shutdown(async {
+                rx.await.ok();
+            });
+        let state = server.state().clone();
+        let handle = tokio::spawn(server.serve());
+        wait_until("bootstrap", || state.is_bootstrapped()).await;
+        let h = Self {
+            mock,
+            state,
+            url,
+            dir,
+        };
+        (h, tx, handle)
+    }
+
+    /// Config file the proxy saves to
+    pub fn config_file(&self) -> PathBuf {
+        self.dir.join("config.toml")
+    }
+
+    /// POST `/v1/messages` and return the status and whole body
+    pub async fn message(&self, body: Value) -> (u16, String) {
+        self.post("/v1/messages", body).await
+    }
+
+    /// POST `body` to `path` and return the status and whole body
+    pub async fn post(&self, path: &str, body: Value) -> (u16, String) {
+        let res = self.send(path, None, body).await;
+        let status = res.status().as_u16();
+        (status, res.text().await.unwrap())
+    }
+
+    /// POST `body` to `path` with `key` as `x-api-key`, if any
+    pub async fn send(&self, path: &str, key: Option<&str>, body: Value) -> rquest::Response {
+        let mut req = rquest::Client::new()
+            .post(format!("{}{}", self.url, path))
+            .json(&body);
+        if let Some(key) = key {
+            req = req.header("x-api-key", key);
+        }
+        req.send().await.unwrap()
+    }
+
+    /// GET `path` with extra `headers` and return the JSON body
+    pub async fn get(&self, path: &str, headers: &[(&str, &str)]) -> Value {
+        let mut req = rquest::Client::new().get(format!("{}{}", self.url, path));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.send().await.unwrap().json().await.unwrap()
+    }
+
+    /// GET `/metrics` with `key` as bearer token, return the status and text
+    pub async fn metrics(&self, key: Option<&str>) -> (u16, String) {
+        let mut req = rquest::Client::new().get(format!("{}/metrics", self.url));
+        if let Some(key) = key {
+            req = req.header("Authorization", format!("Bearer {}", key));
+        }
+        let res = req.send().await.unwrap();
+        let status = res.status().as_u16();
+        (status, res.text().await.unwrap())
+    }
+
+    /// Call the admin API with `key` as bearer token, return the status and JSON body
+    pub async fn admin(
+        &self,
+        method: rquest::Method,
+        path: &str,
+        key: Option<&str>,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let mut req = rquest::Client::new().request(method, format!("{}/admin{}", self.url, path));
+        if let Some(key) = key {
+            req = req.header("Authorization", format!("Bearer {}", key));
+        }
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+        let res = req.send().await.unwrap();
+        let status = res.status().as_u16();
+        (status, res.json().await.unwrap_or_default())
+    }
+
+    /// Organization the proxy is currently logged into
+    pub fn org(&self) -> String {
+        self.state.0.uuid_org.read().clone()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("clewdr-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Default config with `accounts` as the cookie pool and the mock as rproxy
+fn mock_config(
+    mock: &MockServer,
+    accounts: &[(String, Account)],
+    dir: &std::path::Path,
+    edit: impl FnOnce(&mut toml::Table),
+) -> Config {
+    let mut table = toml::Table::try_from(Config::default()).unwrap();
+    let cookies = accounts
+        .iter()
+        .map(|(key, _)| {
+            let mut info = toml::Table::new();
+            info.insert("cookie".to_string(), key.clone().into());
+            toml::Value::Table(info)
+        })
+        .collect::<Vec<_>>();
+    table.insert("cookie_array".to_string(), cookies.into());
+    table.insert("cookie_index".to_string(), 0.into());
+    table.insert("rproxy".to_string(), mock.url.clone().into());
+    edit(&mut table);
+    Config::parse(&toml::to_string(&table).unwrap())
+        .unwrap()
+        .with_file(dir.join("config.toml"))
+}
+
+/// A streamed request with one user turn
+pub fn user_message(content: Value) -> Value {
+    json!({
+        "model": "claude-3-7-sonnet-20250219",
+        "max_tokens": 256,
+        "stream": true,
+        "stop_sequences": [],
+        "messages": [{"role": "user", "content": content}],
+    })
+}
+
+/// Poll `cond` until it holds, panicking after 5 seconds
+pub async fn wait_until(what: &str, mut cond: impl FnMut() -> bool) {
+    for _ in 0..100 {
+        if cond() {
+            return;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
+
+/// Account list shorthand, keys are derived from `tags`
+pub fn accounts(list: &[(char, Account)]) -> Vec<(String, Account)> {
+    list.iter().map(|(t, a)| (session_key(*t), *a)).collect()
+}
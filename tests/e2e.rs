@@ -0,0 +1,1732 @@
+mod common;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use clewdr::{
+    config::{ClientInfo, Config, ThinkingMode},
+    message_client::{ApiMessageClient, WebMessageClient},
+    state::AppState,
+    tokenizer::count_tokens,
+    types::message::{
+        ContentBlock, CountMessageTokensParams, CreateMessageParams, Message, MessageClient,
+        RequiredMessageParams, Role, StopReason, StreamEvent, Tool, ToolChoice,
+    },
+    usage::{ANONYMOUS_CLIENT, UsageTotals},
+};
+use futures::StreamExt;
+use rquest::Method;
+use serde_json::json;
+
+use common::{
+    ADMIN_PASSWORD, Account, Harness, MOCK_API_KEY, MOCK_API_KEY_2, MOCK_FORBIDDEN_MODEL,
+    MOCK_LIMITED_API_KEY, MOCK_REPLY, accounts, org_uuid, session_key, user_message, wait_until,
+    with_admin,
+};
+
+#[tokio::test]
+async fn message_streams_completion() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), with_admin).await;
+    assert!(h.state.is_bootstrapped());
+    assert_eq!(h.org(), org_uuid(0));
+
+    let (status, body) = h.message(user_message(json!("Tell me a story"))).await;
+    assert_eq!(status, 200);
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    // conversation created, then completed
+    assert_eq!(
+        h.mock
+            .state
+            .count("POST /api/organizations/org-0/chat_conversations"),
+        2
+    );
+
+    // the previous conversation is deleted before the next one starts
+    let (_, body) = h.message(user_message(json!("Another one"))).await;
+    assert!(body.contains(MOCK_REPLY));
+    assert_eq!(h.mock.state.count("DELETE /api/organizations/org-0/"), 1);
+
+    // metrics count the requests of this server only
+    let (_, metrics) = h.metrics(Some(ADMIN_PASSWORD)).await;
+    let requests = metrics
+        .lines()
+        .find(|l| l.starts_with("clewdr_requests_total{"))
+        .unwrap_or_default();
+    assert!(
+        requests.contains(r#"model="claude-3-7-sonnet-20250219""#) && requests.ends_with(" 2"),
+        "unexpected metrics: {}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn rate_limited_cookie_rotates() {
+    let resets_at = chrono::Utc::now().timestamp() + 3600;
+    let h = Harness::start(accounts(&[
+        ('a', Account::RateLimited(resets_at)),
+        ('b', Account::Normal),
+    ]))
+    .await;
+    assert_eq!(h.org(), org_uuid(0));
+
+    let (_, body) = h.message(user_message(json!("Hello there"))).await;
+    assert!(
+        body.contains("Too many requests"),
+        "unexpected body: {}",
+        body
+    );
+    wait_until("rotation to the second cookie", || {
+        h.org() == org_uuid(1) && !h.state.is_rotating()
+    })
+    .await;
+    {
+        let config = h.state.0.config.read();
+        assert_eq!(config.index(), 1);
+        assert_eq!(config.cookie_array()[0].reset_time, Some(resets_at));
+        assert!(config.wasted_cookie.is_empty());
+    }
+
+    let (_, body) = h.message(user_message(json!("Hello again"))).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+}
+
+#[tokio::test]
+async fn banned_cookie_is_wasted() {
+    let h = Harness::start(accounts(&[('a', Account::Banned), ('b', Account::Normal)])).await;
+    wait_until("rotation past the banned cookie", || h.org() == org_uuid(1)).await;
+    {
+        let config = h.state.0.config.read();
+        assert_eq!(config.cookie_array_len(), 1);
+        assert_eq!(config.wasted_cookie.len(), 1);
+        assert_eq!(config.wasted_cookie[0].reason.to_string(), "Banned");
+    }
+
+    let (_, body) = h.message(user_message(json!("Hello"))).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+}
+
+#[tokio::test]
+async fn unverified_cookie_is_wasted() {
+    let h = Harness::start(accounts(&[
+        ('a', Account::Unverified),
+        ('b', Account::Normal),
+    ]))
+    .await;
+    wait_until("rotation past the unverified cookie", || {
+        h.org() == org_uuid(1)
+    })
+    .await;
+    let config = h.state.0.config.read();
+    assert_eq!(config.wasted_cookie.len(), 1);
+    assert_eq!(config.wasted_cookie[0].reason.to_string(), "Unverified");
+    // the unverified account never got as far as its organizations
+    assert_eq!(h.mock.state.count("GET /api/organizations"), 1);
+}
+
+#[tokio::test]
+async fn restricted_cookie_is_skipped() {
+    let until = chrono::Utc::now().timestamp() + 3600;
+    let h = Harness::start_with(
+        accounts(&[('a', Account::Restricted(until)), ('b', Account::Normal)]),
+        |table| {
+            table["settings"]
+                .as_table_mut()
+                .unwrap()
+                .insert("skip_restricted".to_string(), true.into());
+        },
+    )
+    .await;
+    wait_until("rotation past the restricted cookie", || {
+        h.org() == org_uuid(1)
+    })
+    .await;
+    let config = h.state.0.config.read();
+    assert!(config.wasted_cookie.is_empty());
+    assert_eq!(config.cookie_array()[0].reset_time, Some(until));
+}
+
+#[tokio::test]
+async fn restricted_cookie_is_kept_by_default() {
+    let until = chrono::Utc::now().timestamp() + 3600;
+    let h = Harness::start(accounts(&[
+        ('a', Account::Restricted(until)),
+        ('b', Account::Normal),
+    ]))
+    .await;
+    assert_eq!(h.org(), org_uuid(0));
+    let (_, body) = h.message(user_message(json!("Hello"))).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+}
+
+#[tokio::test]
+async fn images_are_uploaded() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let image = BASE64_STANDARD.encode(b"\x89PNG\r\n\x1a\nnot really a png");
+    let content = json!([
+        {"type": "text", "text": "What is in this picture?"},
+        {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": image}},
+    ]);
+
+    let (_, body) = h.message(user_message(content)).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(*h.mock.state.uploads.lock(), vec!["image.png".to_string()]);
+    assert_eq!(h.mock.state.count("POST /api/org-0/upload"), 1);
+    let completion = h.mock.state.last_completion.lock().clone().unwrap();
+    assert_eq!(completion["files"], json!(["file-1"]));
+}
+
+/// A PNG of `width` by `height` pixels, of noise when `noisy`
+fn png(width: u32, height: u32, noisy: bool) -> Vec<u8> {
+    let mut seed = 1u32;
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let noise = if noisy { (seed >> 16) as u8 } else { 0 };
+        image::Rgb([x as u8 ^ noise, y as u8, noise])
+    });
+    let mut buf = std::io::Cursor::new(vec![]);
+    image.write_to(&mut buf, image::ImageFormat::Png).unwrap();
+    buf.into_inner()
+}
+
+#[tokio::test]
+async fn url_images_are_fetched_and_prepared() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let wide = png(64, 32, false);
+    let noisy = png(128, 128, true);
+    let mock = &h.mock.state;
+    mock.images
+        .lock()
+        .insert("wide.png".to_string(), wide.clone());
+    mock.images.lock().insert("noisy.png".to_string(), noisy);
+    let image_url = |name: &str| {
+        json!([
+            {"type": "image", "source": {"type": "url", "url": format!("{}/images/{}", h.mock.url, name)}},
+            {"type": "text", "text": "What is in this picture?"},
+        ])
+    };
+
+    // private addresses are refused without a word on why
+    let (status, body) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("failed to fetch"),
+        "unexpected body: {}",
+        body
+    );
+    assert!(
+        !body.contains("127.0.0.1 resolves"),
+        "unexpected body: {}",
+        body
+    );
+    assert_eq!(mock.count("GET /images/"), 0);
+    h.state.0.config.write().images.fetch_allow_hosts = vec!["127.0.0.1".to_string()];
+
+    let (status, body) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    assert_eq!(mock.count("GET /images/wide.png"), 1);
+    assert_eq!(*mock.uploads.lock(), vec!["image.png".to_string()]);
+    assert_eq!(mock.upload_data.lock()[0], wide);
+
+    // downscaled to the longer side, opaque images become JPEGs
+    h.state.0.config.write().images.max_dimension = 16;
+    let (status, _) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 200);
+    assert_eq!(mock.uploads.lock()[1], "image.jpg");
+    let uploaded = image::load_from_memory(&mock.upload_data.lock()[1]).unwrap();
+    assert_eq!((uploaded.width(), uploaded.height()), (16, 8));
+
+    // recompressed until it fits
+    h.state.0.config.write().images.max_dimension = 0;
+    h.state.0.config.write().images.max_bytes = 20_000;
+    let (status, _) = h.message(user_message(image_url("noisy.png"))).await;
+    assert_eq!(status, 200);
+    assert_eq!(mock.uploads.lock()[2], "image.jpg");
+    assert!(mock.upload_data.lock()[2].len() <= 20_000);
+
+    // each image that can't be sent is reported, in order
+    let mut content = image_url("missing.png");
+    content.as_array_mut().unwrap().insert(
+        1,
+        json!({"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": BASE64_STANDARD.encode(&wide)}}),
+    );
+    let (status, body) = h.message(user_message(content)).await;
+    assert_eq!(status, 400);
+    let missing = body.find("image 1: ").expect(&body);
+    let mismatch = body
+        .find("image 2: media_type is image/jpeg but the data is image/png")
+        .expect(&body);
+    assert!(missing < mismatch);
+    assert!(
+        body.contains("image 1: failed to fetch"),
+        "unexpected body: {}",
+        body
+    );
+    assert!(!body.contains("404"), "unexpected body: {}", body);
+    assert_eq!(mock.uploads.lock().len(), 3);
+
+    // every redirect is checked again
+    let redirected = json!([
+        {"type": "image", "source": {"type": "url", "url": format!("{}/redirect/wide.png", h.mock.url)}},
+    ]);
+    let (status, body) = h.message(user_message(redirected.clone())).await;
+    assert_eq!(status, 400, "unexpected body: {}", body);
+    assert_eq!(mock.count("GET /images/wide.png"), 2);
+    h.state
+        .0
+        .config
+        .write()
+        .images
+        .fetch_allow_hosts
+        .push("localhost".to_string());
+    let (status, body) = h.message(user_message(redirected)).await;
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    assert_eq!(mock.count("GET /images/wide.png"), 3);
+    h.state.0.config.write().images.fetch_deny_hosts = vec!["127.0.0.1".to_string()];
+    let (status, _) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 400);
+    assert_eq!(mock.count("GET /images/wide.png"), 3);
+    h.state.0.config.write().images.fetch_deny_hosts.clear();
+
+    // the declared size is checked before decoding
+    h.state.0.config.write().images.max_dimension = 16;
+    h.state.0.config.write().images.decode_max_dimension = 32;
+    let (status, body) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("image is 64x32, above decode_max_dimension 32"),
+        "unexpected body: {}",
+        body
+    );
+    h.state.0.config.write().images.decode_max_dimension = 64;
+    h.state.0.config.write().images.decode_max_bytes = 1000;
+    let (status, body) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("image takes 6144 bytes decoded, above decode_max_bytes 1000"),
+        "unexpected body: {}",
+        body
+    );
+
+    h.state.0.config.write().images.fetch_max_bytes = 100;
+    let (status, body) = h.message(user_message(image_url("wide.png"))).await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("larger than 100 bytes"),
+        "unexpected body: {}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn documents_are_uploaded_or_attached() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let pdf = BASE64_STANDARD.encode(b"%PDF-1.4 not really a pdf");
+    let content = json!([
+        {"type": "document", "title": "report", "source": {"type": "base64", "media_type": "application/pdf", "data": pdf}},
+        {"type": "document", "title": "notes.txt", "context": "Meeting notes", "source": {"type": "text", "media_type": "text/plain", "data": "Ship on Friday"}},
+        {"type": "document", "source": {"type": "content", "content": [{"type": "text", "text": "First"}, {"type": "text", "text": "Second"}]}},
+        {"type": "text", "text": "Summarize these."},
+    ]);
+
+    let (status, body) = h.message(user_message(content.clone())).await;
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    assert_eq!(*h.mock.state.uploads.lock(), vec!["report.pdf".to_string()]);
+    let completion = h.mock.state.last_completion.lock().clone().unwrap();
+    assert_eq!(completion["files"], json!(["file-1"]));
+    let attachments = completion["attachments"].as_array().unwrap();
+    assert_eq!(attachments.len(), 3);
+    assert_eq!(attachments[1]["file_name"], "notes.txt");
+    assert_eq!(
+        attachments[1]["extracted_content"],
+        "Meeting notes\n\nShip on Friday"
+    );
+    assert_eq!(attachments[2]["file_name"], "document.txt");
+    assert_eq!(attachments[2]["extracted_content"], "First\nSecond");
+
+    // a message of documents only does not end the conversation there
+    let (status, _) = h
+        .message(json!({
+            "model": "claude-3-7-sonnet-20250219",
+            "max_tokens": 1024,
+            "messages": [
+                {"role": "user", "content": [content[1].clone()]},
+                {"role": "assistant", "content": "Read it."},
+                {"role": "user", "content": "What is the plan?"},
+            ],
+        }))
+        .await;
+    assert_eq!(status, 200);
+    let completion = h.mock.state.last_completion.lock().clone().unwrap();
+    let prompt = completion["prompt"].as_str().unwrap();
+    assert!(
+        prompt.contains("What is the plan?"),
+        "unexpected prompt: {}",
+        prompt
+    );
+
+    // every file that can't be sent is named
+    let report = content[0].clone();
+    let content = json!([
+        {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "not base64!"}},
+        {"type": "document", "title": "sheet", "source": {"type": "base64", "media_type": "text/csv", "data": pdf}},
+        {"type": "text", "text": "Hello"},
+    ]);
+    let uploads = h.mock.state.count("POST /api/org-0/upload");
+    let (status, body) = h.message(user_message(content)).await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("image 1: invalid base64"),
+        "unexpected body: {}",
+        body
+    );
+    assert!(body.contains("document 1 (sheet): unsupported media type text/csv"));
+    assert_eq!(h.mock.state.count("POST /api/org-0/upload"), uploads);
+
+    h.mock
+        .state
+        .failing_uploads
+        .lock()
+        .push("report.pdf".to_string());
+    let (status, body) = h.message(user_message(json!([report]))).await;
+    assert_eq!(status, 502);
+    assert!(
+        body.contains("document 1 (report)"),
+        "unexpected body: {}",
+        body
+    );
+}
+
+#[tokio::test]
+async fn web_message_client() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let client = WebMessageClient::new(h.state.clone());
+    let params = CreateMessageParams::new(RequiredMessageParams {
+        model: "claude-3-7-sonnet-20250219".to_string(),
+        messages: vec![Message::new_text(Role::User, "Tell me a story")],
+        max_tokens: 256,
+    });
+
+    let message = client.create_message(Some(&params)).await.unwrap();
+    assert_eq!(message.content, vec![ContentBlock::text(MOCK_REPLY)]);
+    assert!(matches!(message.stop_reason, Some(StopReason::EndTurn)));
+    assert_eq!(message.usage.output_tokens, count_tokens(MOCK_REPLY));
+    assert!(message.usage.input_tokens > 0);
+
+    let events = client
+        .create_message_streaming(&params)
+        .await
+        .unwrap()
+        .collect::<Vec<_>>()
+        .await;
+    assert!(matches!(events.last(), Some(Ok(StreamEvent::MessageStop))));
+
+    // an empty prompt is answered without a message stream
+    let empty = CreateMessageParams::new(RequiredMessageParams {
+        model: params.model.clone(),
+        messages: vec![Message::new_text(Role::User, "")],
+        max_tokens: 256,
+    });
+    assert!(client.create_message(Some(&empty)).await.is_err());
+}
+
+#[tokio::test]
+async fn api_message_client() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        let url = table["rproxy"].clone();
+        table.insert("api_rproxy".to_string(), url);
+        table.insert(
+            "api_keys".to_string(),
+            toml::Value::Array(vec![toml::Value::Table(
+                [("key".to_string(), MOCK_API_KEY.into())]
+                    .into_iter()
+                    .collect(),
+            )]),
+        );
+    })
+    .await;
+    let client = ApiMessageClient::new(h.state.clone());
+    let params = CreateMessageParams::new(RequiredMessageParams {
+        model: "claude-3-7-sonnet-20250219".to_string(),
+        messages: vec![Message::new_text(Role::User, "Tell me a story")],
+        max_tokens: 256,
+    });
+
+    let message = client.create_message(Some(&params)).await.unwrap();
+    assert_eq!(message.content, vec![ContentBlock::text(MOCK_REPLY)]);
+    assert_eq!(message.usage.output_tokens, 4);
+    let count = CountMessageTokensParams {
+        model: params.model.clone(),
+        messages: params.messages,
+        ..Default::default()
+    };
+    let tokens = client.count_tokens(Some(&count)).await.unwrap();
+    assert_eq!(tokens.input_tokens, 10);
+    // the web session was never used
+    assert_eq!(h.mock.state.count("POST /api/organizations"), 0);
+}
+
+#[tokio::test]
+async fn only_retryable_errors_fall_back() {
+    let resets_at = chrono::Utc::now().timestamp() + 3600;
+    let h = Harness::start_with(
+        accounts(&[('a', Account::RateLimited(resets_at))]),
+        |table| {
+            let url = table["rproxy"].clone();
+            table.insert("api_rproxy".to_string(), url);
+            table.insert(
+                "api_keys".to_string(),
+                toml::Value::Array(vec![toml::Value::Table(
+                    [("key".to_string(), MOCK_API_KEY.into())]
+                        .into_iter()
+                        .collect(),
+                )]),
+            );
+            table.insert(
+                "fallback".to_string(),
+                toml::Value::try_from(["web", "api"]).unwrap(),
+            );
+        },
+    )
+    .await;
+    // a bad request fails the same way on any backend
+    let mut body = user_message(json!("Hello"));
+    body["temperature"] = 1.5.into();
+    let (status, res) = h.message(body).await;
+    assert_eq!(status, 400, "unexpected body: {}", res);
+    assert_eq!(h.mock.state.count("POST /v1/messages"), 0);
+
+    // a rate limited cookie does not
+    let (status, res) = h.message(user_message(json!("Hello"))).await;
+    assert_eq!(status, 200, "unexpected body: {}", res);
+    assert_eq!(h.mock.state.count("POST /v1/messages"), 1);
+}
+
+#[tokio::test]
+async fn config_without_file_stays_in_memory() {
+    let config = Config::default();
+    assert!(!config.has_file());
+    // nothing is queued for the default config file
+    config.save().unwrap();
+    let default_path = Config::config_path().unwrap();
+    assert!(!config.persister().is_pending(&default_path));
+    let state = AppState::new(config);
+    assert!(state.usage_path().is_none());
+    assert!(state.reload_config().await.is_err());
+}
+
+#[tokio::test]
+async fn graceful_shutdown_drains_and_saves() {
+    let (h, stop, server) = Harness::serve(accounts(&[('a', Account::Normal)])).await;
+    assert!(!h.config_file().exists());
+
+    let (_, body) = h.message(user_message(json!("Hello"))).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(h.mock.state.count("DELETE "), 0);
+
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    // the last conversation is cleaned up and the config written out
+    assert_eq!(h.mock.state.count("DELETE /api/organizations/org-0/"), 1);
+    assert!(h.config_file().exists());
+}
+
+#[tokio::test]
+async fn reload_syncs_cookies_and_keeps_edits() {
+    let h = Harness::start(accounts(&[
+        ('a', Account::Normal),
+        ('b', Account::Normal),
+        ('c', Account::Normal),
+    ]))
+    .await;
+    let path = h.config_file();
+    let persister = h.state.0.config.read().persister().clone();
+    h.state.0.config.read().save().unwrap();
+    persister.flush();
+
+    // drop one cookie, move another to a group and add an alias
+    let mut table: toml::Table = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let cookies = table["cookie_array"].as_array_mut().unwrap();
+    cookies.retain(|c| !c["cookie"].as_str().unwrap().contains(&"c".repeat(86)));
+    cookies[1]
+        .as_table_mut()
+        .unwrap()
+        .insert("group".to_string(), "vip".into());
+    table["model_aliases"]
+        .as_table_mut()
+        .unwrap()
+        .insert("fast".to_string(), "claude-3-5-haiku-20241022".into());
+    std::fs::write(&path, toml::to_string(&table).unwrap()).unwrap();
+
+    // a save before the edit is read does not overwrite it
+    h.state.0.config.read().save().unwrap();
+    persister.flush();
+    assert!(std::fs::read_to_string(&path).unwrap().contains("vip"));
+
+    let changes = h.state.reload_config().await.unwrap();
+    for change in ["1 cookie(s) removed", "1 cookie(s) edited", "model_aliases"] {
+        assert!(
+            changes.iter().any(|c| c == change),
+            "changes: {:?}",
+            changes
+        );
+    }
+    {
+        let config = h.state.0.config.read();
+        let cookies = config.cookie_array();
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[1].group.as_deref(), Some("vip"));
+        assert_eq!(config.index(), 0);
+    }
+    persister.flush();
+    let saved: toml::Table = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["cookie_array"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        saved["model_aliases"]["fast"].as_str(),
+        Some("claude-3-5-haiku-20241022")
+    );
+}
+
+#[tokio::test]
+async fn count_tokens_estimates_prompt() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let messages = json!([
+        {"role": "user", "content": "Hello there"},
+        {"role": "assistant", "content": "Hi"},
+        {"role": "user", "content": "Tell me a story"},
+    ]);
+    let (status, body) = h
+        .post(
+            "/v1/messages/count_tokens",
+            json!({"model": "claude-3-7-sonnet-20250219", "messages": messages}),
+        )
+        .await;
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    let tokens = serde_json::from_str::<serde_json::Value>(&body).unwrap()["input_tokens"]
+        .as_u64()
+        .unwrap();
+    // the role prefixes clewdr adds are counted too
+    let bare = count_tokens("Hello there") + count_tokens("Hi") + count_tokens("Tell me a story");
+    assert!(tokens as u32 > bare, "{} <= {}", tokens, bare);
+
+    let client = WebMessageClient::new(h.state.clone());
+    let mut count = CountMessageTokensParams {
+        model: "claude-3-7-sonnet-20250219".to_string(),
+        messages: serde_json::from_value(messages).unwrap(),
+        ..Default::default()
+    };
+    let counted = client.count_tokens(Some(&count)).await.unwrap();
+    assert_eq!(counted.input_tokens as u64, tokens);
+    // nothing reached claude.ai
+    assert_eq!(h.mock.state.count("POST /api/organizations"), 0);
+
+    // tools are rendered into the prompt, the count matches what is sent
+    count.tools = Some(vec![Tool {
+        name: "get_weather".to_string(),
+        description: Some("Weather forecast".to_string()),
+        input_schema: json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+    }]);
+    let with_tools = client
+        .count_tokens(Some(&count))
+        .await
+        .unwrap()
+        .input_tokens;
+    assert!(with_tools as u64 > tokens, "{} <= {}", with_tools, tokens);
+    let mut params = CreateMessageParams::new(RequiredMessageParams {
+        model: count.model.clone(),
+        messages: count.messages.clone(),
+        max_tokens: 256,
+    });
+    params.tools = count.tools.take();
+    let message = client.create_message(Some(&params)).await.unwrap();
+    assert_eq!(message.usage.input_tokens, with_tools);
+
+    let (status, _) = h
+        .post("/v1/messages/count_tokens", json!({"messages": []}))
+        .await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn usage_is_estimated_and_saved() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let (_, body) = h.message(user_message(json!("Tell me a story"))).await;
+    let output_tokens = count_tokens(MOCK_REPLY);
+    assert!(
+        body.contains(r#""usage":{"input_tokens":"#),
+        "unexpected body: {}",
+        body
+    );
+    assert!(
+        body.contains(&format!(r#""usage":{{"output_tokens":{}}}"#, output_tokens)),
+        "unexpected body: {}",
+        body
+    );
+
+    wait_until("usage of the client", || {
+        h.state
+            .0
+            .usage
+            .read()
+            .clients
+            .contains_key(ANONYMOUS_CLIENT)
+    })
+    .await;
+    let totals = h.state.0.usage.read().clone();
+    let cookie = totals.cookies.values().next().copied().unwrap();
+    assert_eq!(cookie.requests, 1);
+    assert_eq!(cookie.output_tokens, output_tokens as u64);
+    assert!(cookie.input_tokens > 0);
+    // the client sees the same usage the cookie was charged
+    assert_eq!(totals.clients[ANONYMOUS_CLIENT], cookie);
+
+    let path = h.state.usage_path().unwrap();
+    wait_until("usage to be saved", || {
+        UsageTotals::load(&path).clients.get(ANONYMOUS_CLIENT) == Some(&cookie)
+    })
+    .await;
+}
+
+#[test]
+fn client_names_are_unique_and_not_reserved() {
+    let with_clients = |names: &[&str]| {
+        let mut table = toml::Table::try_from(Config::default()).unwrap();
+        let clients = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let client = ClientInfo {
+                    name: name.to_string(),
+                    key: format!("key-{}", i),
+                    ..Default::default()
+                };
+                toml::Value::try_from(client).unwrap()
+            })
+            .collect();
+        table.insert("clients".to_string(), toml::Value::Array(clients));
+        Config::parse(&toml::to_string(&table).unwrap())
+    };
+    assert_eq!(with_clients(&["alice", "bob"]).unwrap().clients.len(), 2);
+    let err = with_clients(&["alice", "bob", "alice"]).unwrap_err();
+    assert!(err.to_string().contains("alice"), "{}", err);
+    let err = with_clients(&["alice", ANONYMOUS_CLIENT]).unwrap_err();
+    assert!(err.to_string().contains("reserved"), "{}", err);
+}
+
+#[tokio::test]
+async fn clients_are_authenticated_and_limited() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        let clients = r#"
+            [[clients]]
+            name = "alice"
+            key = "alice-key"
+            models = ["claude-3-7-sonnet-20250219"]
+            rpm = 2
+
+            [[clients]]
+            name = "bob"
+            key = "bob-key"
+            daily_tokens = 1
+        "#;
+        let clients = clients.parse::<toml::Table>().unwrap();
+        table.insert("clients".to_string(), clients["clients"].clone());
+    })
+    .await;
+    let message = user_message(json!("Hello"));
+    let error_type = |body: &str| {
+        serde_json::from_str::<serde_json::Value>(body).unwrap()["error"]["type"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+
+    let res = h
+        .send("/v1/messages", Some("nobody"), message.clone())
+        .await;
+    assert_eq!(res.status().as_u16(), 401);
+    assert_eq!(
+        error_type(&res.text().await.unwrap()),
+        "authentication_error"
+    );
+
+    let mut opus = message.clone();
+    opus["model"] = "claude-3-opus-20240229".into();
+    let res = h.send("/v1/messages", Some("alice-key"), opus).await;
+    assert_eq!(res.status().as_u16(), 403);
+    assert_eq!(error_type(&res.text().await.unwrap()), "permission_error");
+
+    for _ in 0..2 {
+        let res = h
+            .send("/v1/messages", Some("alice-key"), message.clone())
+            .await;
+        assert!(res.text().await.unwrap().contains(MOCK_REPLY));
+    }
+    let res = h
+        .send("/v1/messages", Some("alice-key"), message.clone())
+        .await;
+    assert_eq!(res.status().as_u16(), 429);
+    assert!(res.headers().contains_key("retry-after"));
+    assert_eq!(error_type(&res.text().await.unwrap()), "rate_limit_error");
+
+    // bob's single token is spent by the first response
+    let res = h
+        .send("/v1/messages", Some("bob-key"), message.clone())
+        .await;
+    assert!(res.text().await.unwrap().contains(MOCK_REPLY));
+    wait_until("usage of bob", || {
+        h.state.0.usage.read().tokens_today("bob") > 0
+    })
+    .await;
+    let res = h
+        .send("/v1/messages", Some("bob-key"), message.clone())
+        .await;
+    assert_eq!(res.status().as_u16(), 429);
+    assert_eq!(error_type(&res.text().await.unwrap()), "rate_limit_error");
+
+    let usage = h.state.0.usage.read().clone();
+    assert_eq!(usage.clients["alice"].requests, 2);
+    assert_eq!(usage.clients["bob"].requests, 1);
+    let recent = h.state.0.request_log.recent();
+    assert_eq!(recent[0].client, "bob");
+    assert_eq!(recent[1].client, "alice");
+}
+
+/// Put cookie `i` of the harness config in `group`
+fn set_group(table: &mut toml::Table, i: usize, group: &str) {
+    table["cookie_array"].as_array_mut().unwrap()[i]
+        .as_table_mut()
+        .unwrap()
+        .insert("group".to_string(), group.into());
+}
+
+#[tokio::test]
+async fn requests_are_routed_to_cookie_groups() {
+    let h = Harness::start_with(
+        accounts(&[
+            ('a', Account::Normal),
+            ('b', Account::Normal),
+            ('c', Account::Normal),
+        ]),
+        |table| {
+            set_group(table, 0, "free");
+            set_group(table, 1, "pro");
+            set_group(table, 2, "free");
+            let rules = r#"
+                proxy_password = "shared"
+
+                [[routes]]
+                models = ["claude-3-opus*"]
+                group = "pro"
+
+                [[routes]]
+                group = "free"
+
+                [[clients]]
+                name = "ghost"
+                key = "ghost-key"
+                cookie_group = "nowhere"
+            "#
+            .parse::<toml::Table>()
+            .unwrap();
+            table.extend(rules);
+        },
+    )
+    .await;
+    let sonnet = user_message(json!("Hello"));
+    let mut opus = sonnet.clone();
+    opus["model"] = "claude-3-opus-20240229".into();
+    let message = |body: serde_json::Value| async {
+        let res = h.send("/v1/messages", Some("shared"), body).await;
+        res.text().await.unwrap()
+    };
+    let completions = |org: usize| {
+        h.mock.state.count(&format!(
+            "POST /api/organizations/{}/chat_conversations/",
+            org_uuid(org)
+        ))
+    };
+
+    let body = message(sonnet.clone()).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(completions(0), 1);
+
+    let body = message(opus).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(h.org(), org_uuid(1));
+    assert_eq!(completions(1), 1);
+
+    // back to the free group, at the cookie after the pro one
+    let body = message(sonnet.clone()).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(h.org(), org_uuid(2));
+    assert_eq!(completions(2), 1);
+
+    let res = h.send("/v1/messages", Some("ghost-key"), sonnet).await;
+    assert_eq!(res.status().as_u16(), 503);
+    assert!(res.text().await.unwrap().contains("group nowhere"));
+}
+
+#[tokio::test]
+async fn concurrent_requests_keep_their_cookie() {
+    let h = Harness::start_with(
+        accounts(&[('a', Account::Normal), ('b', Account::Normal)]),
+        |table| {
+            set_group(table, 0, "free");
+            set_group(table, 1, "pro");
+            let rules = r#"
+                [[routes]]
+                models = ["claude-3-opus*"]
+                group = "pro"
+
+                [[routes]]
+                group = "free"
+            "#
+            .parse::<toml::Table>()
+            .unwrap();
+            table.extend(rules);
+        },
+    )
+    .await;
+    let sonnet = user_message(json!("Hello"));
+    let mut opus = sonnet.clone();
+    opus["model"] = "claude-3-opus-20240229".into();
+    // a switch must not change the session under a request of the other group
+    let requests = (0..8).map(|i| h.message(if i % 2 == 0 { &sonnet } else { &opus }.clone()));
+    for (status, body) in futures::future::join_all(requests).await {
+        assert_eq!(status, 200);
+        assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    }
+    assert_eq!(
+        h.mock
+            .state
+            .count("POST /api/organizations/org-1/chat_conversations/"),
+        4
+    );
+}
+
+#[tokio::test]
+async fn forced_rotation_waits_for_requests() {
+    let h = Harness::start(accounts(&[('a', Account::Normal), ('b', Account::Normal)])).await;
+    let gate = std::sync::Arc::new(tokio::sync::Notify::new());
+    *h.mock.state.gate.lock() = Some(gate.clone());
+    let rotate = async {
+        wait_until("the completion to start", || {
+            h.mock
+                .state
+                .count("POST /api/organizations/org-0/chat_conversations/")
+                == 1
+        })
+        .await;
+        h.state.force_rotate();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(h.state.0.config.read().index(), 0);
+        gate.notify_one();
+    };
+    let ((status, body), ()) = tokio::join!(h.message(user_message(json!("Hello"))), rotate);
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    wait_until("the rotation", || h.org() == org_uuid(1)).await;
+}
+
+#[tokio::test]
+async fn rotation_stays_in_group() {
+    let resets_at = chrono::Utc::now().timestamp() + 3600;
+    let h = Harness::start_with(
+        accounts(&[
+            ('a', Account::RateLimited(resets_at)),
+            ('b', Account::Normal),
+            ('c', Account::Normal),
+        ]),
+        |table| {
+            set_group(table, 0, "free");
+            set_group(table, 1, "pro");
+            set_group(table, 2, "free");
+        },
+    )
+    .await;
+    let (_, body) = h.message(user_message(json!("Hello"))).await;
+    assert!(
+        body.contains("Too many requests"),
+        "unexpected body: {}",
+        body
+    );
+    wait_until("rotation to the other free cookie", || {
+        h.org() == org_uuid(2) && !h.state.is_rotating()
+    })
+    .await;
+    assert_eq!(h.state.0.config.read().index(), 2);
+}
+
+#[tokio::test]
+async fn used_up_group_is_not_left() {
+    let h = Harness::start_with(
+        accounts(&[('a', Account::Banned), ('b', Account::Normal)]),
+        |table| {
+            set_group(table, 0, "free");
+            set_group(table, 1, "pro");
+        },
+    )
+    .await;
+    wait_until("the banned cookie to be wasted", || {
+        h.state.0.config.read().wasted_cookie.len() == 1
+    })
+    .await;
+    // the pro cookie is not bootstrapped in place of the free one
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert_eq!(h.mock.state.count("GET /api/organizations"), 1);
+    assert_ne!(h.org(), org_uuid(1));
+}
+
+#[tokio::test]
+async fn cookies_are_selected_by_model() {
+    let h = Harness::start(accounts(&[
+        ('a', Account::Free("claude-3-7-sonnet-20250219")),
+        ('b', Account::Free("claude-3-5-haiku-20241022")),
+        ('c', Account::Pro),
+    ]))
+    .await;
+    let sonnet = user_message(json!("Hello"));
+    let mut opus = sonnet.clone();
+    opus["model"] = "claude-3-opus-20240229".into();
+    let mut haiku = sonnet.clone();
+    haiku["model"] = "claude-3-5-haiku-20241022".into();
+
+    let (_, body) = h.message(sonnet).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(h.org(), org_uuid(0));
+
+    // the haiku cookie is only bootstrapped to learn its plan, then skipped
+    let (_, body) = h.message(opus).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(h.org(), org_uuid(2));
+
+    // the pro cookie serves any model
+    let (_, body) = h.message(haiku).await;
+    assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    assert_eq!(h.org(), org_uuid(2));
+
+    let config = h.state.0.config.read();
+    assert!(config.wasted_cookie.is_empty());
+    let models = config
+        .cookie_array()
+        .iter()
+        .map(|c| c.model.clone().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        models,
+        [
+            "claude-3-7-sonnet-20250219",
+            "claude-3-5-haiku-20241022",
+            "claude_pro"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unservable_model_is_rejected() {
+    let h = Harness::start(accounts(&[(
+        'a',
+        Account::Free("claude-3-7-sonnet-20250219"),
+    )]))
+    .await;
+    let mut opus = user_message(json!("Hello"));
+    opus["model"] = "claude-3-opus-20240229".into();
+    let (status, body) = h.message(opus).await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("invalid_request_error"),
+        "unexpected body: {}",
+        body
+    );
+    assert!(body.contains("claude-3-opus-20240229"));
+    assert_eq!(h.org(), org_uuid(0));
+}
+
+#[tokio::test]
+async fn cooling_down_model_is_unavailable() {
+    let resets_at = chrono::Utc::now().timestamp() + 3600;
+    let h = Harness::start_with(
+        accounts(&[
+            ('a', Account::Free("claude-3-7-sonnet-20250219")),
+            ('b', Account::Pro),
+        ]),
+        |table| {
+            table["cookie_array"].as_array_mut().unwrap()[1]
+                .as_table_mut()
+                .unwrap()
+                .insert("reset_time".to_string(), resets_at.into());
+        },
+    )
+    .await;
+    let mut opus = user_message(json!("Hello"));
+    opus["model"] = "claude-3-opus-20240229".into();
+    let (status, body) = h.message(opus).await;
+    assert_eq!(status, 503);
+    assert!(body.contains("rate limited"), "unexpected body: {}", body);
+    assert_eq!(h.org(), org_uuid(0));
+}
+
+#[tokio::test]
+async fn aliases_and_model_list() {
+    let h = Harness::start_with(
+        accounts(&[('a', Account::Free("claude-3-7-sonnet-20250219"))]),
+        |table| {
+            let aliases = r#"
+                [model_aliases]
+                gpt-4 = "claude-3-7-sonnet-20250219"
+            "#
+            .parse::<toml::Table>()
+            .unwrap();
+            table.extend(aliases);
+        },
+    )
+    .await;
+    let message = |model: &str| {
+        let mut body = user_message(json!("Hello"));
+        body["model"] = model.into();
+        h.message(body)
+    };
+    for model in ["gpt-4", "sonnet", "claude-3-7-sonnet-latest"] {
+        let (status, body) = message(model).await;
+        assert_eq!(status, 200, "{} was not resolved: {}", model, body);
+        assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    }
+    let (status, body) = message("opus").await;
+    assert_eq!(status, 400);
+    assert!(
+        body.contains("claude-3-opus-20240229"),
+        "unexpected body: {}",
+        body
+    );
+
+    // only the model the free cookie reported is listed
+    let list = h.get("/v1/models", &[]).await;
+    assert_eq!(list["object"], "list");
+    assert_eq!(list["data"].as_array().unwrap().len(), 1);
+    let model = &list["data"][0];
+    assert_eq!(model["id"], "claude-3-7-sonnet-20250219");
+    assert_eq!(model["object"], "model");
+    assert_eq!(model["owned_by"], "anthropic");
+    assert_eq!(model["context_window"], 200_000);
+    assert_eq!(model["plan"], "free");
+    assert!(model["created"].as_i64().unwrap() > 0);
+
+    let list = h
+        .get("/v1/models", &[("anthropic-version", "2023-06-01")])
+        .await;
+    assert_eq!(list["has_more"], false);
+    assert_eq!(list["first_id"], "claude-3-7-sonnet-20250219");
+    let model = &list["data"][0];
+    assert_eq!(model["type"], "model");
+    assert_eq!(model["display_name"], "Claude 3.7 Sonnet");
+    assert_eq!(model["created_at"], "2025-02-19T00:00:00Z");
+}
+
+#[tokio::test]
+async fn sampling_params_are_passed_and_validated() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        table["settings"]
+            .as_table_mut()
+            .unwrap()
+            .insert("pass_params".to_string(), true.into());
+    })
+    .await;
+    let last_completion = || h.mock.state.last_completion.lock().clone().unwrap();
+    let mut body = user_message(json!("Hello"));
+    body["temperature"] = 0.5.into();
+    body["top_k"] = 5.into();
+    body["metadata"] = json!({"user_id": "someone"});
+    let (status, res) = h.message(body.clone()).await;
+    assert_eq!(status, 200, "unexpected body: {}", res);
+    let completion = last_completion();
+    assert_eq!(completion["temperature"], 0.5);
+    assert!(completion.get("top_k").is_none());
+    assert!(completion.get("metadata").is_none());
+
+    for (name, value) in [("temperature", 1.5), ("top_p", -0.1)] {
+        let mut body = user_message(json!("Hello"));
+        body[name] = value.into();
+        let (status, res) = h.message(body).await;
+        assert_eq!(status, 400);
+        assert!(
+            res.contains("invalid_request_error"),
+            "unexpected body: {}",
+            res
+        );
+        assert!(res.contains(name), "unexpected body: {}", res);
+    }
+
+    h.state.0.config.write().settings.pass_params = false;
+    let (status, _) = h.message(body).await;
+    assert_eq!(status, 200);
+    assert!(last_completion().get("temperature").is_none());
+}
+
+/// `data` of every event in an SSE body
+fn sse_events(body: &str) -> Vec<serde_json::Value> {
+    body.lines()
+        .filter_map(|l| l.strip_prefix("data: "))
+        .map(|d| serde_json::from_str(d).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn max_tokens_stops_the_stream() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        table.insert("max_tokens_cap".to_string(), 3.into());
+    })
+    .await;
+    let reply = |events: &[serde_json::Value]| {
+        events
+            .iter()
+            .filter_map(|e| e["delta"]["text"].as_str())
+            .collect::<String>()
+    };
+    let mut body = user_message(json!("Hello"));
+    body["max_tokens"] = 2.into();
+    let (status, res) = h.message(body).await;
+    assert_eq!(status, 200);
+    let events = sse_events(&res);
+    assert_eq!(reply(&events), "Hello from");
+    let types = events
+        .iter()
+        .map(|e| e["type"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        [
+            "message_start",
+            "content_block_start",
+            "content_block_delta",
+            "content_block_stop",
+            "message_delta",
+            "message_stop"
+        ]
+    );
+    let delta = &events[4];
+    assert_eq!(delta["delta"]["stop_reason"], "max_tokens");
+    assert_eq!(delta["usage"]["output_tokens"], 2);
+    wait_until("the conversation to be deleted", || {
+        h.mock.state.count("DELETE /api/organizations/org-0/") == 1
+    })
+    .await;
+
+    // the server-wide cap applies over a larger max_tokens
+    let (_, res) = h.message(user_message(json!("Hello"))).await;
+    assert_eq!(reply(&sse_events(&res)), MOCK_REPLY);
+    assert!(res.contains("end_turn"));
+    h.state.0.config.write().max_tokens_cap = 1;
+    let (_, res) = h.message(user_message(json!("Hello"))).await;
+    assert_eq!(reply(&sse_events(&res)), "Hello");
+}
+
+#[tokio::test]
+async fn tool_calls_are_emulated() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    *h.mock.state.reply.lock() = [
+        "Let me check. <func",
+        "tion_calls>\n<invoke name=\"get_weather\">\n<parameter name=\"city\">Paris</parameter>\n",
+        "<parameter name=\"days\">3</para",
+        "meter>\n</invoke>\n</function_calls>",
+        " and more text",
+    ]
+    .map(String::from)
+    .to_vec();
+    let prompt = || {
+        let completion = h.mock.state.last_completion.lock().clone().unwrap();
+        format!(
+            "{}{}",
+            completion["attachments"][0]["extracted_content"]
+                .as_str()
+                .unwrap(),
+            completion["prompt"].as_str().unwrap()
+        )
+    };
+    let weather = Tool {
+        name: "get_weather".to_string(),
+        description: Some("Weather forecast".to_string()),
+        input_schema: json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}, "days": {"type": "integer"}},
+        }),
+    };
+    let client = WebMessageClient::new(h.state.clone());
+    let params = CreateMessageParams::new(RequiredMessageParams {
+        model: "claude-3-7-sonnet-20250219".to_string(),
+        messages: vec![Message::new_text(Role::User, "Weather in Paris?")],
+        max_tokens: 256,
+    })
+    .with_tools(vec![weather])
+    .with_tool_choice(ToolChoice::Any);
+
+    let message = client.create_message(Some(&params)).await.unwrap();
+    assert!(matches!(message.stop_reason, Some(StopReason::ToolUse)));
+    assert_eq!(message.content.len(), 2);
+    assert_eq!(message.content[0], ContentBlock::text("Let me check. "));
+    let ContentBlock::ToolUse { id, name, input } = &message.content[1] else {
+        panic!("not a tool call: {:?}", message.content[1]);
+    };
+    assert!(id.starts_with("toolu_"));
+    assert_eq!(name, "get_weather");
+    assert_eq!(input, &json!({"city": "Paris", "days": 3}));
+    let sent = prompt();
+    assert!(sent.contains("<functions>"), "unexpected prompt: {}", sent);
+    assert!(sent.contains("Weather forecast"));
+    assert!(sent.contains("You must call at least one"));
+    wait_until("the conversation to be deleted", || {
+        h.mock.state.count("DELETE /api/organizations/org-0/") == 1
+    })
+    .await;
+
+    // calls to tools the request did not define stay text
+    *h.mock.state.reply.lock() = vec![
+        "<function_calls>\n<invoke name=\"rm_rf\">\n</invoke>\n</function_calls>".to_string(),
+        " Done.".to_string(),
+    ];
+    let message = client.create_message(Some(&params)).await.unwrap();
+    assert!(matches!(message.stop_reason, Some(StopReason::EndTurn)));
+    let [ContentBlock::Text { text }] = &message.content[..] else {
+        panic!("not a single text block: {:?}", message.content);
+    };
+    assert!(
+        text.contains("rm_rf") && text.ends_with(" Done."),
+        "{}",
+        text
+    );
+
+    // the call and its result are rendered in the next turn
+    h.mock.state.reply.lock().clear();
+    let mut body = user_message(json!("Weather in Paris?"));
+    body["tools"] = json!([{"name": "get_weather", "input_schema": {"type": "object"}}]);
+    body["messages"]
+        .as_array_mut()
+        .unwrap()
+        .extend([
+            json!({"role": "assistant", "content": [
+                {"type": "tool_use", "id": id, "name": "get_weather", "input": {"city": "Paris"}},
+            ]}),
+            json!({"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": id, "content": [{"type": "text", "text": "Sunny"}]},
+            ]}),
+        ]);
+    let (status, res) = h.message(body.clone()).await;
+    assert_eq!(status, 200, "unexpected body: {}", res);
+    assert!(res.contains(MOCK_REPLY));
+    let sent = prompt();
+    assert!(
+        sent.contains("<invoke name=\"get_weather\">\n<parameter name=\"city\">Paris</parameter>"),
+        "unexpected prompt: {}",
+        sent
+    );
+    assert!(sent.contains("<tool_name>get_weather</tool_name>\n<stdout>\nSunny"));
+
+    body["tool_choice"] = json!({"type": "tool", "name": "nope"});
+    let (status, res) = h.message(body).await;
+    assert_eq!(status, 400);
+    assert!(
+        res.contains("no tool named nope"),
+        "unexpected body: {}",
+        res
+    );
+}
+
+#[tokio::test]
+async fn thinking_is_passed_within_budget() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        let url = table["rproxy"].clone();
+        table.insert("api_rproxy".to_string(), url);
+        table.insert(
+            "api_keys".to_string(),
+            toml::Value::Array(vec![toml::Value::Table(
+                [("key".to_string(), MOCK_API_KEY.into())]
+                    .into_iter()
+                    .collect(),
+            )]),
+        );
+    })
+    .await;
+    let words = (0..1500).map(|_| " word").collect::<String>();
+    *h.mock.state.thinking.lock() = vec!["Let me think.".to_string(), words];
+    let mut body = user_message(json!("Hello"));
+    body["max_tokens"] = 4096.into();
+    body["thinking"] = json!({"type": "enabled", "budget_tokens": 1024});
+    let thinking_of = |events: &[serde_json::Value]| {
+        events
+            .iter()
+            .filter_map(|e| e["delta"]["thinking"].as_str())
+            .collect::<String>()
+    };
+
+    let (status, res) = h.message(body.clone()).await;
+    assert_eq!(status, 200, "unexpected body: {}", res);
+    let conversation = h.mock.state.last_conversation.lock().clone().unwrap();
+    assert_eq!(conversation["paprika_mode"], "extended");
+    let events = sse_events(&res);
+    assert_eq!(events[1]["content_block"]["type"], "thinking");
+    let thinking = thinking_of(&events);
+    assert!(thinking.starts_with("Let me think. word"));
+    assert_eq!(count_tokens(&thinking), 1024);
+    let signature = events
+        .iter()
+        .position(|e| e["delta"]["type"] == "signature_delta")
+        .unwrap();
+    assert_eq!(events[signature + 1]["type"], "content_block_stop");
+    assert_eq!(events[signature + 1]["index"], 0);
+    assert_eq!(events[signature + 2]["content_block"]["type"], "text");
+    assert_eq!(events[signature + 2]["index"], 1);
+
+    // the official API would reject the placeholder signature, so the
+    // thinking is not sent there
+    let mut to_api = body.clone();
+    to_api["stream"] = false.into();
+    to_api["messages"].as_array_mut().unwrap().extend([
+        json!({"role": "assistant", "content": [
+            {"type": "thinking", "thinking": thinking, "signature": events[signature]["delta"]["signature"]},
+            {"type": "text", "text": "Visible answer"},
+        ]}),
+        json!({"role": "user", "content": "And then?"}),
+    ]);
+    let res = rquest::Client::new()
+        .post(format!("{}/v1/messages", h.url))
+        .header("x-clewdr-backend", "api")
+        .json(&to_api)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status().as_u16(), 200);
+    let sent = h.mock.state.last_api_request.lock().clone().unwrap();
+    assert_eq!(
+        sent["messages"][1]["content"],
+        json!([{"type": "text", "text": "Visible answer"}])
+    );
+
+    // earlier thinking is accepted and left out of the prompt
+    let mut next = body.clone();
+    next["messages"].as_array_mut().unwrap().extend([
+        json!({"role": "assistant", "content": [
+            {"type": "thinking", "thinking": "Secret thoughts", "signature": "c2ln"},
+            {"type": "text", "text": "Visible answer"},
+        ]}),
+        json!({"role": "user", "content": "And then?"}),
+    ]);
+    let (status, _) = h.message(next).await;
+    assert_eq!(status, 200);
+    let completion = h.mock.state.last_completion.lock().clone().unwrap();
+    let prompt = completion["prompt"].as_str().unwrap();
+    assert!(
+        prompt.contains("Visible answer"),
+        "unexpected prompt: {}",
+        prompt
+    );
+    assert!(!prompt.contains("Secret thoughts"));
+
+    h.state.0.config.write().thinking_mode = ThinkingMode::Strip;
+    let (_, res) = h.message(body.clone()).await;
+    let events = sse_events(&res);
+    assert!(thinking_of(&events).is_empty());
+    assert_eq!(events[1]["content_block"]["type"], "text");
+    assert!(
+        events
+            .iter()
+            .all(|e| e["index"].is_null() || e["index"] == 0)
+    );
+
+    h.state.0.config.write().thinking_mode = ThinkingMode::Inline;
+    let (_, res) = h.message(body.clone()).await;
+    let events = sse_events(&res);
+    let text = events
+        .iter()
+        .filter_map(|e| e["delta"]["text"].as_str())
+        .collect::<String>();
+    assert!(
+        text.starts_with("<thinking>\nLet me think."),
+        "unexpected text: {}",
+        text
+    );
+    assert!(text.contains("\n</thinking>\n\n"));
+    assert!(thinking_of(&events).is_empty());
+
+    body["thinking"]["budget_tokens"] = 100.into();
+    let (status, res) = h.message(body).await;
+    assert_eq!(status, 400);
+    assert!(res.contains("budget_tokens"), "unexpected body: {}", res);
+}
+
+#[tokio::test]
+async fn admin_api_rejects_bad_passwords() {
+    let h = Harness::start(accounts(&[('a', Account::Normal)])).await;
+    let (status, _) = h
+        .admin(Method::GET, "/status", Some("anything"), None)
+        .await;
+    assert_eq!(status, 403, "the admin API is off without admin_password");
+
+    h.state.0.config.write().admin_password = ADMIN_PASSWORD.to_string();
+    for key in [None, Some("wrong"), Some("mock-admiN"), Some("mock-admin2")] {
+        let (status, body) = h.admin(Method::GET, "/status", key, None).await;
+        assert_eq!(status, 401, "{:?} got {}", key, body);
+    }
+    let (status, body) = h
+        .admin(Method::GET, "/status", Some(ADMIN_PASSWORD), None)
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["cookie_count"], 1);
+}
+
+#[tokio::test]
+async fn admin_api_manages_cookies() {
+    let h = Harness::start_with(
+        accounts(&[
+            ('a', Account::Normal),
+            ('b', Account::Normal),
+            ('c', Account::Normal),
+        ]),
+        with_admin,
+    )
+    .await;
+    let admin = |method, path, body| h.admin(method, path, Some(ADMIN_PASSWORD), body);
+    let listed = |body: &serde_json::Value, list: &str| {
+        body[list]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["cookie"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let cookie = |tag| format!("sessionKey={}", session_key(tag));
+    let (a, b, c) = (cookie('a'), cookie('b'), cookie('c'));
+
+    let (status, _) = admin(Method::DELETE, "/cookies", Some(json!({"cookie": c}))).await;
+    assert_eq!(status, 200);
+    let (_, body) = admin(Method::GET, "/cookies", None).await;
+    assert_eq!(listed(&body, "cookie_array"), vec![a.clone(), b.clone()]);
+    let (status, _) = admin(Method::DELETE, "/cookies", Some(json!({"cookie": c}))).await;
+    assert_eq!(status, 404);
+
+    let (status, _) = admin(Method::POST, "/cookies", Some(json!({"cookie": c}))).await;
+    assert_eq!(status, 201);
+    let (status, _) = admin(Method::POST, "/cookies", Some(json!({"cookie": c}))).await;
+    assert_eq!(status, 409);
+    let (status, _) = admin(Method::POST, "/cookies", Some(json!({"cookie": "nope"}))).await;
+    assert_eq!(status, 400);
+
+    let waste = json!({"cookie": b, "reason": "Banned"});
+    let (status, _) = admin(Method::POST, "/cookies/waste", Some(waste)).await;
+    assert_eq!(status, 200);
+    let (_, body) = admin(Method::GET, "/cookies", None).await;
+    assert_eq!(listed(&body, "cookie_array"), vec![a.clone(), c.clone()]);
+    assert_eq!(listed(&body, "wasted_cookie"), vec![b.clone()]);
+    assert_eq!(body["wasted_cookie"][0]["reason"], "Banned");
+
+    let (status, _) = admin(Method::POST, "/cookies/restore", Some(json!({"cookie": b}))).await;
+    assert_eq!(status, 200);
+    let (status, _) = admin(Method::POST, "/cookies/restore", Some(json!({"cookie": b}))).await;
+    assert_eq!(status, 404);
+    let (_, body) = admin(Method::GET, "/cookies", None).await;
+    assert_eq!(listed(&body, "cookie_array"), vec![a, c, b]);
+    assert!(listed(&body, "wasted_cookie").is_empty());
+
+    // removing the current cookie moves the session to another one
+    assert_eq!(h.org(), org_uuid(0));
+    let first = json!({"cookie": cookie('a')});
+    let (status, _) = admin(Method::DELETE, "/cookies", Some(first)).await;
+    assert_eq!(status, 200);
+    wait_until("bootstrap of the next cookie", || h.org() == org_uuid(2)).await;
+}
+
+#[tokio::test]
+async fn admin_api_rotates_and_edits_settings() {
+    let h = Harness::start_with(
+        accounts(&[('a', Account::Normal), ('b', Account::Normal)]),
+        with_admin,
+    )
+    .await;
+    let admin = |method, path, body| h.admin(method, path, Some(ADMIN_PASSWORD), body);
+
+    let (status, _) = admin(Method::POST, "/rotate", None).await;
+    assert_eq!(status, 200);
+    wait_until("the rotation", || h.org() == org_uuid(1)).await;
+    let (_, body) = admin(Method::GET, "/status", None).await;
+    assert_eq!(body["cookie_index"], 1);
+
+    let (status, mut settings) = admin(Method::GET, "/settings", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(settings["preserve_chats"], false);
+    settings["preserve_chats"] = true.into();
+    settings["padtxt"] = "500".into();
+    let (status, body) = admin(Method::PUT, "/settings", Some(settings.clone())).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, settings);
+    assert!(h.state.0.config.read().settings.preserve_chats);
+    assert_eq!(h.state.0.config.read().settings.padtxt, "500");
+    let (_, body) = admin(Method::GET, "/settings", None).await;
+    assert_eq!(body, settings);
+    let (status, _) = admin(Method::PUT, "/settings", Some(json!({"padtxt": 1}))).await;
+    assert_eq!(status, 422);
+}
+
+/// Send requests to the mock official API with `keys`
+fn use_api_keys(table: &mut toml::Table, keys: &[&str]) {
+    let url = table["rproxy"].clone();
+    table.insert("api_rproxy".to_string(), url);
+    let keys = keys
+        .iter()
+        .map(|k| toml::Value::Table([("key".to_string(), (*k).into())].into_iter().collect()))
+        .collect();
+    table.insert("api_keys".to_string(), toml::Value::Array(keys));
+}
+
+/// POST `/v1/messages` pinned to the API backend, return the status and body
+async fn api_message(h: &Harness, body: serde_json::Value) -> (u16, String) {
+    let res = rquest::Client::new()
+        .post(format!("{}/v1/messages", h.url))
+        .header("x-clewdr-backend", "api")
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    let status = res.status().as_u16();
+    (status, res.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn api_keys_are_wasted_only_on_401() {
+    let revoked = "sk-ant-api03-revoked";
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        use_api_keys(table, &[revoked, MOCK_API_KEY])
+    })
+    .await;
+    let used = || std::mem::take(&mut *h.mock.state.api_keys_used.lock());
+
+    let (status, body) = api_message(&h, user_message(json!("Hello"))).await;
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    assert_eq!(used(), vec![revoked, MOCK_API_KEY]);
+    {
+        let config = h.state.0.config.read();
+        assert_eq!(config.wasted_api_keys, vec![revoked.to_string()]);
+        assert_eq!(config.api_keys.len(), 1);
+    }
+
+    // a 403 is about the request, the client gets it and the key stays
+    let mut forbidden = user_message(json!("Hello"));
+    forbidden["model"] = MOCK_FORBIDDEN_MODEL.into();
+    let (status, body) = api_message(&h, forbidden).await;
+    assert_eq!(status, 403);
+    assert!(
+        body.contains("permission_error"),
+        "unexpected body: {}",
+        body
+    );
+    assert_eq!(used(), vec![MOCK_API_KEY]);
+    assert_eq!(h.state.0.config.read().api_keys.len(), 1);
+    assert_eq!(h.state.0.config.read().wasted_api_keys.len(), 1);
+}
+
+#[tokio::test]
+async fn metrics_are_admin_only_and_count_upstream_errors_by_class() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        use_api_keys(table, &[MOCK_API_KEY]);
+        with_admin(table);
+    })
+    .await;
+    assert_eq!(h.metrics(None).await.0, 401);
+    assert_eq!(h.metrics(Some("wrong")).await.0, 401);
+
+    let mut forbidden = user_message(json!("Hello"));
+    forbidden["model"] = MOCK_FORBIDDEN_MODEL.into();
+    assert_eq!(api_message(&h, forbidden).await.0, 403);
+    let (status, metrics) = h.metrics(Some(ADMIN_PASSWORD)).await;
+    assert_eq!(status, 200);
+    let requests = metrics
+        .lines()
+        .find(|l| l.starts_with("clewdr_requests_total{"))
+        .unwrap_or_default();
+    assert!(
+        requests.contains(r#"status="4xx""#) && requests.ends_with(" 1"),
+        "unexpected metrics: {}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn api_keys_rotate_and_cool_down() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        use_api_keys(table, &[MOCK_LIMITED_API_KEY, MOCK_API_KEY, MOCK_API_KEY_2])
+    })
+    .await;
+    let used = || std::mem::take(&mut *h.mock.state.api_keys_used.lock());
+    let reset_time = || {
+        h.state.0.config.read().api_keys[0]
+            .reset_time
+            .unwrap_or_default()
+    };
+
+    let (status, body) = api_message(&h, user_message(json!("Hello"))).await;
+    assert_eq!(status, 200, "unexpected body: {}", body);
+    assert_eq!(used()[0], MOCK_LIMITED_API_KEY);
+    let now = chrono::Utc::now().timestamp();
+    assert!((now + 110..=now + 120).contains(&reset_time()));
+    assert!(h.state.0.config.read().wasted_api_keys.is_empty());
+
+    // the paused key is skipped, the others share the requests
+    for _ in 0..4 {
+        let (status, _) = api_message(&h, user_message(json!("Hello"))).await;
+        assert_eq!(status, 200);
+    }
+    let keys = used();
+    assert_eq!(keys.len(), 4);
+    assert!(!keys.contains(&MOCK_LIMITED_API_KEY.to_string()));
+    for key in [MOCK_API_KEY, MOCK_API_KEY_2] {
+        assert!(keys.contains(&key.to_string()), "{:?}", keys);
+    }
+
+    // and used again once the reset passed
+    h.state.0.config.write().api_keys[0].reset_time = Some(now - 1);
+    for _ in 0..3 {
+        let (status, _) = api_message(&h, user_message(json!("Hello"))).await;
+        assert_eq!(status, 200);
+    }
+    assert!(used().contains(&MOCK_LIMITED_API_KEY.to_string()));
+    assert!(reset_time() > now);
+}
+
+#[tokio::test]
+async fn web_outages_fall_back_to_the_api() {
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        use_api_keys(table, &[MOCK_API_KEY]);
+        table.insert(
+            "fallback".to_string(),
+            toml::Value::try_from(["web", "api"]).unwrap(),
+        );
+    })
+    .await;
+    // overloaded with a JSON error, then down with a page
+    for status in [529, 503] {
+        *h.mock.state.completion_status.lock() = Some(status);
+        let (code, body) = h.message(user_message(json!("Hello"))).await;
+        assert_eq!(code, 200, "{} was not retried: {}", status, body);
+        assert!(body.contains(MOCK_REPLY), "unexpected body: {}", body);
+    }
+    assert_eq!(h.mock.state.count("POST /v1/messages"), 2);
+}
+
+#[tokio::test]
+async fn probes_need_no_key() {
+    // proxy_password alone leaves the API open
+    let h = Harness::start_with(accounts(&[('a', Account::Normal)]), |table| {
+        table.insert("proxy_password".to_string(), "shared".into());
+    })
+    .await;
+    let (status, _) = h.message(user_message(json!("Hello"))).await;
+    assert_eq!(status, 200);
+
+    h.state.0.config.write().clients = vec![ClientInfo {
+        name: "alice".to_string(),
+        key: "alice-key".to_string(),
+        ..Default::default()
+    }];
+    let (status, _) = h.message(user_message(json!("Hello"))).await;
+    assert_eq!(status, 401);
+    let res = h
+        .send("/v1/messages", Some("shared"), user_message(json!("Hello")))
+        .await;
+    assert_eq!(res.status().as_u16(), 200);
+    assert_eq!(h.get("/healthz", &[]).await["status"], "ok");
+    assert_eq!(h.get("/readyz", &[]).await["ready"], true);
+}
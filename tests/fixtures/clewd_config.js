@@ -0,0 +1,64 @@
+/*
+* https://rentry.org/teralomaniac_clewd
+* https://github.com/teralomaniac/clewd
+*/
+
+// SET YOUR COOKIE BELOW
+
+module.exports = {
+    "Cookie": "",
+    "CookieArray": [
+        "sk-ant-REDACTED",
+        "claude-3-opus@sk-ant-REDACTED",
+        ""
+    ],
+    "WastedCookie": [
+        "Banned@sk-ant-REDACTED",
+        "sk-ant-REDACTED"
+    ],
+    "unknownModels": [
+        "claude-2.1"
+    ],
+    "CookieCounter": 5,
+    "CookieIndex": 2,
+    "ProxyPassword": "hunter2",
+    "Ip": "127.0.0.1",
+    "Port": 8444,
+    "localtunnel": false,
+    "BufferSize": 1,
+    "SystemInterval": 3,
+    "rProxy": "",
+    "api_rProxy": null,
+    "placeholder_token": "",
+    "placeholder_byte": "",
+    "PromptExperimentFirst": "",
+    "PromptExperimentNext": "",
+    "PersonalityFormat": "{{char}}'s personality: {{personality}}",
+    "ScenarioFormat": "Dialogue scenario: {{scenario}}",
+    "Settings": {
+        "RenewAlways": false,
+        "RetryRegenerate": false,
+        "PromptExperiments": true,
+        "SystemExperiments": true,
+        "PreventImperson": false,
+        "AllSamples": false,
+        "NoSamples": false,
+        "StripAssistant": false,
+        "StripHuman": false,
+        "PassParams": true,
+        "ClearFlags": true,
+        "PreserveChats": false,
+        "LogMessages": null,
+        "FullColon": true,
+        "padtxt": "1000,1000,15000",
+        "xmlPlot": true,
+        "SkipRestricted": false,
+        "Artifacts": false,
+        "Superfetch": true
+    }
+}
+
+/*
+ BufferSize
+ * tweak this number if your stream is choppy (e.g. \n)
+*/